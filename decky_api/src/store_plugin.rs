@@ -1,21 +1,117 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 
 pub type StorePluginList = Vec<StorePlugin>;
 
+/// A plugin listed by a store.
+///
+/// Missing or `null` fields deserialize to their defaults, so that additions to the upstream schema don't break parsing.
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct StorePlugin {
     pub id: usize,
+    #[serde(deserialize_with = "null_as_default")]
     pub name: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub versions: Vec<StorePluginVersion>,
+    #[serde(deserialize_with = "null_as_default")]
     pub author: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub description: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub tags: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
     pub image_url: String,
+    #[serde(deserialize_with = "null_as_visible")]
+    pub visible: bool,
+    /// Total downloads of all versions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<u64>,
+    /// Total updates to any version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updates: Option<u64>,
+    /// RFC 3339 timestamp of the first version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// RFC 3339 timestamp of the latest version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+impl Default for StorePlugin {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            name: String::new(),
+            versions: Vec::new(),
+            author: String::new(),
+            description: String::new(),
+            tags: Vec::new(),
+            image_url: String::new(),
+            visible: default_visible(),
+            downloads: None,
+            updates: None,
+            created: None,
+            updated: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct StorePluginVersion {
+    #[serde(deserialize_with = "null_as_default")]
     pub name: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub hash: String,
     pub artifact: Option<String>,
+    /// Retracted version which should no longer be installed
+    #[serde(skip_serializing_if = "std::ops::Not::not", deserialize_with = "null_as_default")]
+    pub yanked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yanked_reason: Option<String>,
+    /// RFC 3339 timestamp of when this version was published
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updates: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+}
+
+fn default_visible() -> bool {
+    true
+}
+
+fn null_as_default<'de, D: Deserializer<'de>, T: Default + Deserialize<'de>>(deserializer: D) -> Result<T, D::Error> {
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+fn null_as_visible<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(Option::<bool>::deserialize(deserializer)?.unwrap_or_else(default_visible))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerant_deserialization() {
+        let json = r#"{
+            "id": 7,
+            "name": "Example",
+            "author": null,
+            "versions": [{"name": "1.0.0", "hash": "abc", "created": "2023-01-01T00:00:00+00:00", "downloads": 3, "future_field": 1}],
+            "visible": null,
+            "some_new_upstream_field": {"nested": true}
+        }"#;
+        let plugin: StorePlugin = serde_json::from_str(json).expect("StorePlugin parse error");
+        assert_eq!(plugin.id, 7);
+        assert_eq!(plugin.author, "");
+        assert!(plugin.visible);
+        assert!(plugin.tags.is_empty());
+        assert_eq!(plugin.versions[0].downloads, Some(3));
+        assert!(plugin.versions[0].artifact.is_none());
+    }
 }
@@ -2,6 +2,8 @@ use clap::{Parser, Subcommand, Args};
 //use std::io::Write as _;
 use std::fmt::Write as _;
 
+use crate::logging::{LogFormat, LogLevel};
+
 /// An alternative plugin store
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None, propagate_version = true)]
@@ -12,9 +14,30 @@ pub struct CliArgs {
     /// Local server port (default: 222252)
     #[arg(name = "port", short, long)]
     pub server_port: Option<u16>,
+    /// API token for publishing plugins (publishing is disabled when not set)
+    #[arg(name = "token", long, env = "NOT_DECKY_TOKEN", hide_env_values = true)]
+    pub api_token: Option<String>,
+    /// Log level (default: info)
+    #[arg(name = "log-level", long, value_enum)]
+    pub log_level: Option<LogLevel>,
+    /// Log message format (default: plain)
+    #[arg(name = "log-format", long, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Log to this file, rotating it as it grows, instead of stderr
+    #[arg(name = "log-file", long, conflicts_with = "journald")]
+    pub log_file: Option<String>,
+    /// Log to journald instead of stderr
+    #[arg(name = "journald", long)]
+    pub journald: bool,
+    /// Log every request
+    #[arg(name = "access-log", long)]
+    pub access_log: bool,
+    /// Store configuration file (TOML, or JSON with a .json extension)
+    #[arg(name = "config", long)]
+    pub config: Option<std::path::PathBuf>,
     /// Storage adapter
    #[command(subcommand)]
-   pub storage: StorageArgs,
+   pub storage: Option<StorageArgs>,
 }
 
 impl CliArgs {
@@ -50,14 +73,14 @@ impl StorageArgs {
                 'm' | '+' => Self::Merge(MergeArgs::from_descriptor(chars)?),
                 c => return Err(format!("Unexpected char {}, expected a descriptor prefix from {{d f p e m}}", c)),
             };
-            log::info!("Parsed descriptor as {}", desc.clone().to_descriptor());
+            log::info!("Parsed descriptor as {}", desc.to_descriptor());
             Ok(desc)
         } else {
-            Err(format!("Empty storage descriptor"))
+            Err("Empty storage descriptor".to_owned())
         }
     }
 
-    pub fn to_descriptor(self) -> String {
+    pub fn to_descriptor(&self) -> String {
         match self {
             Self::Default => "d".to_owned(),
             Self::Filesystem(fs) => format!("f{}", fs.to_descriptor()),
@@ -76,6 +99,9 @@ pub struct FilesystemArgs {
     pub domain_root: String,
     #[arg(name = "stats", long)]
     pub enable_stats: bool,
+    /// Watch the plugins folder for changes instead of re-reading it on every request
+    #[arg(name = "watch", long)]
+    pub enable_watch: bool,
 }
 
 impl FilesystemArgs {
@@ -85,22 +111,28 @@ impl FilesystemArgs {
                 return Err(format!("Expected {{, got {}", char1));
             }
         } else {
-            return Err(format!("Filesystem descriptor too short"));
+            return Err("Filesystem descriptor too short".to_owned());
         }
         let mut root = None;
         let mut domain = None;
         let mut stats = false;
+        let mut watch = false;
         let mut buffer = Vec::<char>::new();
         let mut for_variable: Option<String> = None;
         let mut in_string = false;
         for c in chars {
             //println!("Handling char: '{}' (in_string? {}, root: {:?}, domain: {:?}, stats: {:?}, buf: {:?}, for_variable: {:?})", c, in_string, root, domain, stats, buffer, for_variable);
             match c {
-                '}' => if !in_string {return
-                    Ok(Self {
+                '}' => if !in_string {
+                    if let Some(var) = for_variable.take() {
+                        let value: String = buffer.drain(..).collect();
+                        Self::apply_variable(&var, value, &mut root, &mut domain, &mut stats, &mut watch)?;
+                    }
+                    return Ok(Self {
                         root: root.unwrap_or_else(|| "./store".into()),
                         domain_root: domain.unwrap_or_else(|| "http://localhost:22252".into()),
                         enable_stats: stats,
+                        enable_watch: watch,
                     })
                 } else { buffer.push('}') },
                 '\"' => in_string = !in_string,
@@ -112,17 +144,11 @@ impl FilesystemArgs {
                         for_variable = Some(value);
                     }
                 } else { buffer.push('=') },
-                ',' => if !in_string && !buffer.is_empty() {
+                ',' => if !in_string {
                     let value: String = buffer.drain(..).collect();
                     if let Some(var) = for_variable.take() {
-                        let var_trimmed = var.trim();
-                        match &var_trimmed as &str {
-                            "r" | "root" => root = Some(value),
-                            "d" | "domain" => domain = Some(value),
-                            "s" | "stats" => stats = value == "1" || value == "y",
-                            v => return Err(format!("Unexpected variable name {} in filesystem descriptor", v)),
-                        }
-                    } else {
+                        Self::apply_variable(&var, value, &mut root, &mut domain, &mut stats, &mut watch)?;
+                    } else if !value.is_empty() {
                         return Err("Unexpected , in filesystem descriptor".to_owned())
                     }
                 } else { buffer.push(',') }
@@ -132,8 +158,19 @@ impl FilesystemArgs {
         Err("Unexpected end of descriptor".to_owned())
     }
 
-    fn to_descriptor(self) -> String {
-        format!("{{root=\"{}\",domain=\"{}\",stats={},}}", self.root, self.domain_root, self.enable_stats as u8)
+    fn apply_variable(var: &str, value: String, root: &mut Option<String>, domain: &mut Option<String>, stats: &mut bool, watch: &mut bool) -> Result<(), String> {
+        match var.trim() {
+            "r" | "root" => *root = Some(value),
+            "d" | "domain" => *domain = Some(value),
+            "s" | "stats" => *stats = value == "1" || value == "y",
+            "w" | "watch" => *watch = value == "1" || value == "y",
+            v => return Err(format!("Unexpected variable name {} in filesystem descriptor", v)),
+        }
+        Ok(())
+    }
+
+    fn to_descriptor(&self) -> String {
+        format!("{{root=\"{}\",domain=\"{}\",stats={},watch={},}}", self.root, self.domain_root, self.enable_stats as u8, self.enable_watch as u8)
     }
 }
 
@@ -142,29 +179,47 @@ pub struct ProxyArgs {
     /// Proxy offerings from another store
     #[arg(name = "store", long, default_value_t = {"https://plugins.deckbrew.xyz".into()})]
     pub proxy_store: String,
+    /// Serve artifacts and images through this server's domain, caching them locally
+    #[arg(name = "domain", long)]
+    pub domain_root: Option<String>,
+    /// Folder for cached artifacts and images (default: ./proxy_cache)
+    #[arg(name = "cache-folder", long)]
+    pub cache_root: Option<String>,
 }
 
 impl ProxyArgs {
+    // p{store} or p{store,domain} or p{store,domain,cache folder}
     fn from_descriptor(chars: &mut std::str::Chars) -> Result<Self, String> {
         if let Some(char1) = chars.next() {
             if char1 != '{' {
                 return Err(format!("Expected {{, got {}", char1));
             }
         } else {
-            return Err(format!("Proxy descriptor too short"));
+            return Err("Proxy descriptor too short".to_owned());
         }
+        let mut parts: Vec<String> = Vec::new();
         let mut buffer = Vec::new();
         let mut escaped = false;
         for c in chars {
             match c {
-                '}' => if escaped {
-                    buffer.push('}')
-                } else {
+                '}' | ',' if escaped => {
+                    escaped = false;
+                    buffer.push(c)
+                },
+                '}' => {
+                    parts.push(buffer.drain(..).collect());
+                    if parts.len() > 3 {
+                        return Err(format!("Too many proxy descriptor values ({}), expected at most 3", parts.len()));
+                    }
+                    let mut parts = parts.drain(..).map(|p| if p.is_empty() { None } else { Some(p) });
                     return
                         Ok(Self {
-                            proxy_store: if buffer.is_empty() { "https://plugins.deckbrew.xyz".into() } else { buffer.iter().collect() }
+                            proxy_store: parts.next().flatten().unwrap_or_else(|| "https://plugins.deckbrew.xyz".into()),
+                            domain_root: parts.next().flatten(),
+                            cache_root: parts.next().flatten(),
                         })
                 }
+                ',' => parts.push(buffer.drain(..).collect()),
                 '\\' => escaped = true,
                 c => {
                     if escaped {
@@ -178,8 +233,17 @@ impl ProxyArgs {
         Err("Unexpected end of descriptor".to_owned())
     }
 
-    fn to_descriptor(self) -> String {
-        format!("{{{}}}", self.proxy_store)
+    fn to_descriptor(&self) -> String {
+        let escape = |s: &str| s.replace(',', "\\,").replace('}', "\\}");
+        let mut out = format!("{{{}", escape(&self.proxy_store));
+        if self.domain_root.is_some() || self.cache_root.is_some() {
+            write!(&mut out, ",{}", escape(self.domain_root.as_deref().unwrap_or(""))).unwrap();
+        }
+        if let Some(cache_root) = &self.cache_root {
+            write!(&mut out, ",{}", escape(cache_root)).unwrap();
+        }
+        out.push('}');
+        out
     }
 }
 
@@ -196,7 +260,7 @@ impl MergeArgs {
                 return Err(format!("Expected [, got {}", char1));
             }
         } else {
-            return Err(format!("Merge descriptor too short"));
+            return Err("Merge descriptor too short".to_owned());
         }
         let mut others = Vec::new();
         loop {
@@ -232,9 +296,9 @@ impl MergeArgs {
         Err("Unexpected end of descriptor".to_owned())
     }
 
-    fn to_descriptor(self) -> String {
+    fn to_descriptor(&self) -> String {
         let mut out = "[".to_owned();
-        for descriptor in self.settings {
+        for descriptor in &self.settings {
             write!(&mut out, "({})", descriptor).unwrap();
         }
         write!(&mut out, "]").unwrap();
@@ -282,6 +346,11 @@ mod tests {
         let descriptor = "{}";
         let parsed = ProxyArgs::from_descriptor(&mut descriptor.chars());
         parsed.expect("ProxyArgs parse error");
+        let descriptor = "{https://plugins.deckbrew.xyz,http://192.168.0.128:22252,./proxy_cache}";
+        let parsed = ProxyArgs::from_descriptor(&mut descriptor.chars()).expect("ProxyArgs parse error");
+        assert_eq!(parsed.domain_root.as_deref(), Some("http://192.168.0.128:22252"));
+        assert_eq!(parsed.cache_root.as_deref(), Some("./proxy_cache"));
+        assert_eq!(parsed.to_descriptor(), descriptor);
     }
 
     #[test]
@@ -0,0 +1,438 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::cli::{CliArgs, StorageArgs};
+use crate::logging::{LogFormat, LogLevel};
+use crate::storage::{FilterRules, MergePolicy, Overrides};
+
+pub const DEFAULT_PORT: u16 = 22252;
+const DEFAULT_STORE_URL: &str = "https://plugins.deckbrew.xyz";
+
+/// Store configuration, loaded from a TOML or JSON file (`--config store.toml`)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    pub storage: StorageConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub port: Option<u16>,
+    /// API token for publishing and admin endpoints (disabled when not set)
+    pub api_token: Option<String>,
+    /// Serve HTTPS, on its own port or instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, reloaded when it changes
+    pub cert: String,
+    /// PEM private key, reloaded when it changes
+    pub key: String,
+    /// HTTPS port next to the plain HTTP `server.port`, which serves HTTPS instead when not set
+    pub port: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub level: LogLevel,
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub output: LogOutput,
+    /// Log every request with client address, route, status, bytes and duration
+    #[serde(default)]
+    pub access: bool,
+}
+
+/// Where log messages go, selected by its `type` field
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    File(LogFileConfig),
+    Journald,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: String,
+    /// Size in MiB at which the file is rotated
+    #[serde(default = "default_log_max_size")]
+    pub max_size_mb: u64,
+    /// Number of rotated files to keep
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+/// A node of the storage tree, selected by its `type` field
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Filesystem(FilesystemConfig),
+    Proxy(ProxyConfig),
+    Merge(MergeConfig),
+    Empty,
+    Cache(CacheConfig),
+    Filter(FilterConfig),
+    Overrides(OverridesConfig),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FilesystemConfig {
+    #[serde(default = "default_root")]
+    pub root: String,
+    #[serde(default = "default_domain")]
+    pub domain: String,
+    #[serde(default)]
+    pub stats: bool,
+    /// Watch the plugins folder for changes instead of re-reading it on every request
+    #[serde(default)]
+    pub watch: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    #[serde(default = "default_store_url")]
+    pub store: String,
+    /// Serve artifacts and images through this domain, caching them locally
+    pub domain: Option<String>,
+    pub cache_folder: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MergeConfig {
+    pub stores: Vec<StorageConfig>,
+    /// How to combine plugins offered by more than one store: `priority`, `union` or `newest`
+    #[serde(default)]
+    pub policy: MergePolicy,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Seconds before cached results are refreshed in the background
+    pub duration: i64,
+    pub inner: Box<StorageConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Only keep plugins and versions matching these patterns
+    #[serde(default)]
+    pub allow: FilterRules,
+    /// Remove plugins and versions matching these patterns
+    #[serde(default)]
+    pub deny: FilterRules,
+    pub inner: Box<StorageConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OverridesConfig {
+    /// TOML or JSON file with per-plugin overrides, re-read when it changes
+    pub file: String,
+    /// Serve override image files through this domain
+    pub domain: Option<String>,
+    pub inner: Box<StorageConfig>,
+}
+
+fn default_root() -> String {
+    "./store".into()
+}
+
+fn default_domain() -> String {
+    "http://localhost:22252".into()
+}
+
+fn default_store_url() -> String {
+    DEFAULT_STORE_URL.into()
+}
+
+fn default_log_max_size() -> u64 {
+    10
+}
+
+fn default_log_keep() -> usize {
+    5
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config `{}`: {}", path.display(), e))?;
+        let config = if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            Self::from_json(&text)
+        } else {
+            Self::from_toml(&text)
+        }.map_err(|e| format!("Invalid config `{}`: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let config: Self = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Build the config from command line arguments, with `--config` taking the place of the storage subcommand
+    pub fn from_cli(args: &CliArgs) -> Result<Self, String> {
+        let mut config = match (&args.config, &args.storage) {
+            (Some(_), Some(_)) => return Err("Use either --config or a storage subcommand, not both".to_owned()),
+            (Some(path), None) => Self::load(path)?,
+            (None, Some(storage)) => Self {
+                server: ServerConfig::default(),
+                log: LogConfig::default(),
+                storage: StorageConfig::from_args(storage)?,
+            },
+            (None, None) => return Err("Missing storage settings, use --config or a storage subcommand".to_owned()),
+        };
+        // command line arguments override the config file
+        if let Some(cache_duration) = args.cache_duration {
+            config.storage = StorageConfig::Cache(CacheConfig {
+                duration: cache_duration,
+                inner: Box::new(config.storage),
+            });
+        }
+        if args.server_port.is_some() {
+            config.server.port = args.server_port;
+        }
+        if args.api_token.is_some() {
+            config.server.api_token = args.api_token.clone();
+        }
+        if let Some(level) = args.log_level {
+            config.log.level = level;
+        }
+        if let Some(format) = args.log_format {
+            config.log.format = format;
+        }
+        if let Some(path) = &args.log_file {
+            config.log.output = LogOutput::File(LogFileConfig {
+                path: path.clone(),
+                max_size_mb: default_log_max_size(),
+                keep: default_log_keep(),
+            });
+        } else if args.journald {
+            config.log.output = LogOutput::Journald;
+        }
+        if args.access_log {
+            config.log.access = true;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server.port == Some(0) {
+            return Err("server.port: must not be 0".to_owned());
+        }
+        if self.server.api_token.as_ref().map(|t| t.trim().is_empty()).unwrap_or(false) {
+            return Err("server.api_token: must not be empty".to_owned());
+        }
+        if let Some(tls) = &self.server.tls {
+            if tls.cert.is_empty() {
+                return Err("server.tls.cert: must not be empty".to_owned());
+            }
+            if tls.key.is_empty() {
+                return Err("server.tls.key: must not be empty".to_owned());
+            }
+            if tls.port == Some(0) {
+                return Err("server.tls.port: must not be 0".to_owned());
+            }
+            let http_port = self.server.port.unwrap_or(DEFAULT_PORT);
+            if tls.port == Some(http_port) {
+                return Err(format!("server.tls.port: must differ from server.port {}", http_port));
+            }
+        }
+        if let LogOutput::File(file) = &self.log.output {
+            if file.path.is_empty() {
+                return Err("log.output.path: must not be empty".to_owned());
+            }
+            if file.max_size_mb == 0 {
+                return Err("log.output.max_size_mb: must not be 0".to_owned());
+            }
+        }
+        self.storage.validate("storage")
+    }
+}
+
+impl StorageConfig {
+    pub fn from_args(args: &StorageArgs) -> Result<Self, String> {
+        Ok(match args {
+            StorageArgs::Default => Self::Filesystem(FilesystemConfig {
+                root: "./store".into(),
+                domain: "http://192.168.0.128:22252".into(),
+                stats: true,
+                watch: false,
+            }),
+            StorageArgs::Filesystem(fs) => Self::Filesystem(FilesystemConfig {
+                root: fs.root.clone(),
+                domain: fs.domain_root.clone(),
+                stats: fs.enable_stats,
+                watch: fs.enable_watch,
+            }),
+            StorageArgs::Proxy(px) => Self::Proxy(ProxyConfig {
+                store: px.proxy_store.clone(),
+                domain: px.domain_root.clone(),
+                cache_folder: px.cache_root.clone(),
+            }),
+            StorageArgs::Empty => Self::Empty,
+            StorageArgs::Merge(ls) => Self::Merge(MergeConfig {
+                stores: ls.generate_args()?
+                    .iter()
+                    .map(Self::from_args)
+                    .collect::<Result<_, _>>()?,
+                policy: MergePolicy::default(),
+            }),
+        })
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        match self {
+            Self::Filesystem(fs) => {
+                if fs.root.is_empty() {
+                    return Err(format!("{}.root: must not be empty", path));
+                }
+                validate_url(&fs.domain, &format!("{}.domain", path))
+            },
+            Self::Proxy(px) => {
+                validate_url(&px.store, &format!("{}.store", path))?;
+                if let Some(domain) = &px.domain {
+                    validate_url(domain, &format!("{}.domain", path))?;
+                } else if px.cache_folder.is_some() {
+                    return Err(format!("{}.cache_folder: requires domain to be set", path));
+                }
+                Ok(())
+            },
+            Self::Merge(ls) => {
+                if ls.stores.len() < 2 {
+                    return Err(format!("{}.stores: merge needs at least 2 stores, got {}", path, ls.stores.len()));
+                }
+                for (i, store) in ls.stores.iter().enumerate() {
+                    store.validate(&format!("{}.stores[{}]", path, i))?;
+                }
+                Ok(())
+            },
+            Self::Empty => Ok(()),
+            Self::Cache(c) => {
+                if c.duration <= 0 {
+                    return Err(format!("{}.duration: must be a positive number of seconds, got {}", path, c.duration));
+                }
+                c.inner.validate(&format!("{}.inner", path))
+            },
+            Self::Filter(f) => {
+                if f.allow.is_empty() && f.deny.is_empty() {
+                    return Err(format!("{}: filter needs at least one allow or deny pattern", path));
+                }
+                for (list, rules) in [("allow", &f.allow), ("deny", &f.deny)] {
+                    for (field, patterns) in [("names", &rules.names), ("authors", &rules.authors), ("tags", &rules.tags), ("versions", &rules.versions)] {
+                        if let Some(i) = patterns.iter().position(|pattern| pattern.trim().is_empty()) {
+                            return Err(format!("{}.{}.{}[{}]: must not be empty", path, list, field, i));
+                        }
+                    }
+                }
+                f.inner.validate(&format!("{}.inner", path))
+            },
+            Self::Overrides(o) => {
+                Overrides::load(Path::new(&o.file))
+                    .map_err(|e| format!("{}.file: failed to load `{}`: {}", path, o.file, e))?;
+                if let Some(domain) = &o.domain {
+                    validate_url(domain, &format!("{}.domain", path))?;
+                }
+                o.inner.validate(&format!("{}.inner", path))
+            },
+        }
+    }
+}
+
+fn validate_url(url: &str, path: &str) -> Result<(), String> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        Err(format!("{}: `{}` must start with http:// or https://", path, url))
+    } else if url.ends_with('/') {
+        Err(format!("{}: `{}` must not end with /", path, url))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_toml_config() {
+        let text = r#"
+            [server]
+            port = 8080
+
+            [storage]
+            type = "cache"
+            duration = 60
+
+            [storage.inner]
+            type = "merge"
+            policy = "newest"
+
+            [[storage.inner.stores]]
+            type = "filesystem"
+            root = "./store"
+            stats = true
+
+            [[storage.inner.stores]]
+            type = "proxy"
+
+            [[storage.inner.stores]]
+            type = "empty"
+        "#;
+        let config = Config::from_toml(text).expect("Config parse error");
+        assert_eq!(config.server.port, Some(8080));
+        assert_eq!(config.log.level, LogLevel::Info);
+        assert!(matches!(config.log.output, LogOutput::Stderr));
+        if let StorageConfig::Cache(cache) = config.storage {
+            if let StorageConfig::Merge(merge) = *cache.inner {
+                assert_eq!(merge.stores.len(), 3);
+                assert_eq!(merge.policy, MergePolicy::Newest);
+                return;
+            }
+        }
+        panic!("Unexpected storage tree");
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let err = Config::from_toml("[storage]\ntype = \"merge\"\nstores = [{ type = \"empty\" }]\n").unwrap_err();
+        assert!(err.contains("storage.stores"), "{}", err);
+        let err = Config::from_toml("[storage]\ntype = \"filesystem\"\nrot = \"./store\"\n").unwrap_err();
+        assert!(err.contains("line 1") && err.contains("unknown field `rot`"), "{}", err);
+        let err = Config::from_json(r#"{"storage": {"type": "proxy", "store": "plugins.deckbrew.xyz"}}"#).unwrap_err();
+        assert!(err.contains("storage.store"), "{}", err);
+        let err = Config::from_toml("[storage]\ntype = \"filter\"\ninner = { type = \"empty\" }\n").unwrap_err();
+        assert!(err.contains("at least one allow or deny pattern"), "{}", err);
+        let err = Config::from_toml("[server.tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\nport = 22252\n[storage]\ntype = \"empty\"\n").unwrap_err();
+        assert!(err.contains("server.tls.port"), "{}", err);
+        let err = Config::from_toml("[log]\nlevel = \"verbose\"\n[storage]\ntype = \"empty\"\n").unwrap_err();
+        assert!(err.contains("unknown variant `verbose`"), "{}", err);
+    }
+}
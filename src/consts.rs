@@ -1,2 +1,2 @@
-pub const PACKAGE_NAME: &'static str = env!("CARGO_PKG_NAME");
-pub const PACKAGE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub const PACKAGE_NAME: &str = env!("CARGO_PKG_NAME");
+pub const PACKAGE_VERSION: &str = env!("CARGO_PKG_VERSION");
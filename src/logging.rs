@@ -0,0 +1,330 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::config::{LogConfig, LogOutput};
+use crate::consts;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => Self::Off,
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Trace => Self::Trace,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per message
+    #[default]
+    Plain,
+    /// One JSON object per message
+    Json,
+}
+
+/// A handled request, for the access log
+pub struct AccessEntry<'a> {
+    pub client: Option<String>,
+    pub method: &'a str,
+    pub path: &'a str,
+    /// Route pattern the request matched, e.g. `/plugins/{name}/{version}/{hash}.zip`
+    pub route: &'a str,
+    pub status: u16,
+    pub bytes: Option<u64>,
+    pub duration: Duration,
+}
+
+/// Install the process-wide logger described by the config
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let level = LevelFilter::from(config.level);
+    if LOGGER.set(Logger::new(config)?).is_err() {
+        return Err(io::Error::other("Logging is already set up"));
+    }
+    log::set_logger(LOGGER.get().expect("Logger must be set")).map_err(|e| io::Error::other(e.to_string()))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Write a request to the access log, if it is enabled
+pub fn access(entry: &AccessEntry) {
+    if let Some(logger) = LOGGER.get() {
+        logger.access(entry);
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    access: bool,
+    output: Output,
+}
+
+enum Output {
+    Stderr,
+    File(Mutex<RotatingFile>),
+    Journald(UnixDatagram),
+}
+
+impl Logger {
+    fn new(config: &LogConfig) -> io::Result<Self> {
+        let output = match &config.output {
+            LogOutput::Stderr => Output::Stderr,
+            LogOutput::File(file) => Output::File(Mutex::new(RotatingFile::open(
+                file.path.clone().into(),
+                file.max_size_mb * 1024 * 1024,
+                file.keep,
+            )?)),
+            LogOutput::Journald => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(JOURNALD_SOCKET)
+                    .map_err(|e| io::Error::new(e.kind(), format!("Failed to connect to journald at {}: {}", JOURNALD_SOCKET, e)))?;
+                Output::Journald(socket)
+            },
+        };
+        Ok(Self {
+            level: config.level.into(),
+            format: config.format,
+            access: config.access,
+            output,
+        })
+    }
+
+    fn access(&self, entry: &AccessEntry) {
+        if !self.access {
+            return;
+        }
+        let bytes = entry.bytes.map(|b| b.to_string()).unwrap_or_else(|| "-".to_owned());
+        let duration_ms = entry.duration.as_secs_f64() * 1000.0;
+        let message = format!(
+            "{} \"{} {}\" {} {} {:.1}ms",
+            entry.client.as_deref().unwrap_or("-"), entry.method, entry.path, entry.status, bytes, duration_ms,
+        );
+        let fields = json!({
+            "client": entry.client,
+            "method": entry.method,
+            "path": entry.path,
+            "route": entry.route,
+            "status": entry.status,
+            "bytes": entry.bytes,
+            "duration_ms": duration_ms,
+        });
+        let Value::Object(fields) = fields else { unreachable!() };
+        self.emit(Level::Info, "access", &message, fields);
+    }
+
+    fn emit(&self, level: Level, target: &str, message: &str, fields: Map<String, Value>) {
+        // journald keeps its own timestamps
+        let time = match self.output {
+            Output::Journald(_) => None,
+            _ => Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        };
+        let line = match self.format {
+            LogFormat::Plain => match &time {
+                Some(time) => format!("{} {:<5} [{}] {}", time, level, target, message),
+                None => format!("[{}] {}", target, message),
+            },
+            LogFormat::Json => {
+                let mut object = Map::new();
+                if let Some(time) = time {
+                    object.insert("time".to_owned(), time.into());
+                }
+                object.insert("level".to_owned(), level.as_str().into());
+                object.insert("target".to_owned(), target.into());
+                object.insert("message".to_owned(), message.into());
+                object.extend(fields);
+                Value::Object(object).to_string()
+            },
+        };
+        let result = match &self.output {
+            Output::Stderr => writeln!(io::stderr().lock(), "{}", line),
+            Output::File(file) => file.lock().expect("Failed to acquire log file lock").write_line(&line),
+            Output::Journald(socket) => socket.send(&journald_entry(level, target, &line)).map(|_| ()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write log message `{}`: {}", line, e);
+        }
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.emit(record.level(), record.target(), &record.args().to_string(), Map::new());
+        }
+    }
+
+    fn flush(&self) {
+        if let Output::File(file) = &self.output {
+            let _ = file.lock().expect("Failed to acquire log file lock").file.flush();
+        }
+    }
+}
+
+/// A log file which is moved to `<path>.1` (and older files to `<path>.2` and so on) once it grows too big
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to open log file `{}`: {}", path.display(), e)))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.keep).rev() {
+            let older = self.rotated(i);
+            if older.exists() {
+                std::fs::rename(&older, self.rotated(i + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, i: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", i));
+        name.into()
+    }
+}
+
+/// Encode a message for the journald native protocol
+fn journald_entry(level: Level, target: &str, message: &str) -> Vec<u8> {
+    let priority = match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    };
+    let mut entry = Vec::new();
+    for (key, value) in [("PRIORITY", priority), ("SYSLOG_IDENTIFIER", consts::PACKAGE_NAME), ("LOG_TARGET", target), ("MESSAGE", message)] {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // multi-line values are sent with their length instead of after `=`
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LogFileConfig;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn rotating_json_log() {
+        let dir = TempDir::new("log");
+        let path = dir.join("store.log");
+        let logger = Logger::new(&LogConfig {
+            level: LogLevel::Info,
+            format: LogFormat::Json,
+            output: LogOutput::File(LogFileConfig {
+                path: path.to_string_lossy().into_owned(),
+                max_size_mb: 1,
+                keep: 1,
+            }),
+            access: true,
+        }).unwrap();
+        logger.access(&AccessEntry {
+            client: Some("192.168.0.42".to_owned()),
+            method: "GET",
+            path: "/plugins",
+            route: "/plugins",
+            status: 200,
+            bytes: Some(1234),
+            duration: Duration::from_millis(5),
+        });
+        let text = std::fs::read_to_string(&path).unwrap();
+        let entry: Value = serde_json::from_str(text.trim()).unwrap();
+        assert_eq!(entry["target"], "access");
+        assert_eq!(entry["client"], "192.168.0.42");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes"], 1234);
+
+        // only the newest rotated file is kept
+        if let Output::File(file) = &logger.output {
+            file.lock().unwrap().max_size = 1;
+        }
+        logger.emit(Level::Warn, "test", "second", Map::new());
+        logger.emit(Level::Warn, "test", "third", Map::new());
+        assert!(std::fs::read_to_string(&path).unwrap().contains("third"));
+        assert!(std::fs::read_to_string(dir.join("store.log.1")).unwrap().contains("second"));
+        assert!(!dir.join("store.log.2").exists());
+    }
+
+    #[test]
+    fn journald_encoding() {
+        let entry = journald_entry(Level::Error, "not_decky", "two\nlines");
+        let text = String::from_utf8_lossy(&entry);
+        assert!(text.starts_with("PRIORITY=3\nSYSLOG_IDENTIFIER="), "{}", text);
+        assert!(entry.ends_with(b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n"));
+    }
+}
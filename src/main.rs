@@ -1,57 +1,119 @@
 mod cli;
+mod config;
 mod consts;
+mod logging;
+mod metrics;
 mod not_decky;
 mod storage;
+#[cfg(test)]
+mod test_util;
+mod tls;
+
+use std::sync::Arc;
 
 use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
-use simplelog::{LevelFilter, WriteLogger};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::Service;
 
 #[get("/version_info")]
 async fn hello() -> impl Responder {
     HttpResponse::Ok().body(format!("{} v{}", consts::PACKAGE_NAME, consts::PACKAGE_VERSION))
 }
 
-fn build_storage_box(storage: &cli::StorageArgs) -> Box<dyn storage::IStorage> {
-    log::debug!("storage args {:?}", storage);
+fn build_storage_box(storage: &config::StorageConfig) -> Box<dyn storage::IStorage> {
+    log::debug!("storage config {:?}", storage);
     match storage {
-        cli::StorageArgs::Default => Box::new(storage::FileStorage::new(
-            "./store".into(),
-            "http://192.168.0.128:22252".into(),
-            true,
+        config::StorageConfig::Filesystem(fs) => {
+            let files = storage::FileStorage::new(
+                fs.root.clone().into(),
+                fs.domain.clone(),
+                fs.stats,
+            );
+            if fs.watch {
+                Box::new(files.with_watch())
+            } else {
+                Box::new(files)
+            }
+        },
+        config::StorageConfig::Proxy(px) => {
+            let proxy = storage::ProxiedStorage::new(px.store.clone());
+            if let Some(domain_root) = &px.domain {
+                let cache_root = px.cache_folder.clone().unwrap_or_else(|| "./proxy_cache".into());
+                Box::new(proxy.with_pull_through(domain_root.clone(), cache_root.into()))
+            } else {
+                Box::new(proxy)
+            }
+        },
+        config::StorageConfig::Empty => Box::new(storage::EmptyStorage),
+        config::StorageConfig::Merge(ls) => Box::new(storage::MergedStorage::new(
+            ls.stores.iter()
+                .map(build_storage_box)
+                .collect()
+        ).with_policy(ls.policy)),
+        config::StorageConfig::Cache(c) => Box::new(storage::CachedStorage::new(
+            c.duration,
+            build_storage_box(&c.inner),
         )),
-        cli::StorageArgs::Filesystem(fs) => Box::new(storage::FileStorage::new(
-            fs.root.clone().into(),
-            fs.domain_root.clone().into(),
-            fs.enable_stats,
+        config::StorageConfig::Filter(f) => Box::new(storage::FilteredStorage::new(
+            build_storage_box(&f.inner),
+            f.allow.clone(),
+            f.deny.clone(),
         )),
-        cli::StorageArgs::Proxy(px) => Box::new(storage::ProxiedStorage::new(
-            px.proxy_store.clone(),
+        config::StorageConfig::Overrides(o) => Box::new(storage::OverrideStorage::new(
+            build_storage_box(&o.inner),
+            o.file.clone().into(),
+            o.domain.clone(),
         )),
-        cli::StorageArgs::Empty => Box::new(storage::EmptyStorage),
-        cli::StorageArgs::Merge(ls) => Box::new(storage::MergedStorage::new(
-            ls.generate_args()
-                .expect("Bad descriptor")
-                .drain(..)
-                .map(|args| build_storage_box(&args))
-                .collect()
-        ))
     }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = cli::CliArgs::get();
-    let log_filepath = std::path::Path::new("/tmp").join(format!("{}.log", consts::PACKAGE_NAME));
-    WriteLogger::init(
-        LevelFilter::Debug,
-        Default::default(),
-        std::fs::File::create(&log_filepath).unwrap(),
-    )
-    .unwrap();
+    let config = match config::Config::from_cli(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let port = config.server.port.unwrap_or(config::DEFAULT_PORT);
+    // without its own port, HTTPS takes the place of plain HTTP
+    let (http_port, https) = match &config.server.tls {
+        Some(tls) => {
+            let certificates = tls::Certificates::load(tls.cert.clone().into(), tls.key.clone().into())
+                .and_then(|certs| Ok((certs.acceptor()?, certs)));
+            match certificates {
+                Ok((acceptor, certs)) => {
+                    certs.spawn_reloader(tls::RELOAD_INTERVAL);
+                    match tls.port {
+                        Some(https_port) => (Some(port), Some((https_port, acceptor))),
+                        None => (None, Some((port, acceptor))),
+                    }
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                }
+            }
+        },
+        None => (Some(port), None),
+    };
 
-    println!("Logging to {}", log_filepath.display());
+    if let Err(e) = logging::init(&config.log) {
+        eprintln!("Failed to set up logging: {}", e);
+        std::process::exit(2);
+    }
+
+    // one storage graph shared by all workers, so caches and statistics are process-wide
+    let storage_data: Arc<dyn storage::IStorage> = build_storage_box(&config.storage).into();
 
-    HttpServer::new(move || {
+    let api_token = web::Data::new(not_decky::ApiToken(config.server.api_token.clone()));
+    if config.server.api_token.is_none() {
+        log::info!("No API token configured, publishing is disabled");
+    }
+
+    let mut server = HttpServer::new(move || {
         let cors = actix_cors::Cors::default()
             //.allowed_origin("https://steamloopback.host")
             .allow_any_origin()
@@ -60,25 +122,56 @@ async fn main() -> std::io::Result<()> {
             .allow_any_header()
             .expose_any_header();
 
-        let storage_data: Box<dyn storage::IStorage> = build_storage_box(&args.storage);
-
-        let storage_data = if let Some(cache_duration) = args.cache_duration {
-            Box::new(storage::CachedStorage::new(cache_duration, storage_data))
-        } else {
-            storage_data
-        };
-
         App::new()
             .wrap(cors)
-            .app_data(web::Data::new(storage_data))
+            .wrap_fn(|req, srv| {
+                let start = std::time::Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    // label by route pattern, so e.g. every artifact request counts towards the same route
+                    let route = response.request().match_pattern().unwrap_or_else(|| "unmatched".to_owned());
+                    let bytes = match response.response().body().size() {
+                        BodySize::Sized(size) => Some(size),
+                        _ => None,
+                    };
+                    let elapsed = start.elapsed();
+                    metrics::METRICS.observe_request(&route, response.status().as_u16(), elapsed, bytes);
+                    logging::access(&logging::AccessEntry {
+                        client: response.request().peer_addr().map(|addr| addr.ip().to_string()),
+                        method: response.request().method().as_str(),
+                        path: response.request().path(),
+                        route: &route,
+                        status: response.status().as_u16(),
+                        bytes,
+                        duration: elapsed,
+                    });
+                    Ok(response)
+                }
+            })
+            .app_data(web::Data::from(storage_data.clone()))
+            .app_data(api_token.clone())
             .service(hello)
             .service(not_decky::decky_index)
             .service(not_decky::decky_plugins)
             .service(not_decky::decky_artifact)
             .service(not_decky::decky_image)
             .service(not_decky::decky_statistics)
-    })
-    .bind(("0.0.0.0", args.server_port.unwrap_or(22252)))?
-    .run()
-    .await
+            .service(not_decky::decky_metrics)
+            .service(not_decky::decky_health)
+            .service(not_decky::decky_publish)
+            .service(not_decky::decky_delete_version)
+            .service(not_decky::decky_hide_version)
+            .service(not_decky::decky_unhide_version)
+            .service(not_decky::decky_yank_version)
+            .service(not_decky::decky_unyank_version)
+    });
+    if let Some(http_port) = http_port {
+        server = server.bind(("0.0.0.0", http_port))?;
+    }
+    if let Some((https_port, acceptor)) = https {
+        log::info!("Serving HTTPS on port {}", https_port);
+        server = server.bind_openssl(("0.0.0.0", https_port), acceptor)?;
+    }
+    server.run().await
 }
@@ -0,0 +1,107 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+
+/// Process-wide Prometheus metrics, served at `/metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// HTTP requests by route pattern and status code
+    pub requests: IntCounterVec,
+    /// HTTP request handling time by route pattern
+    pub request_duration: HistogramVec,
+    /// Response body bytes by route pattern
+    pub bytes_served: IntCounterVec,
+    /// `CachedStorage` lookups by cache (plugins, statistics, artifacts, images) and result (hit, miss)
+    pub cache_lookups: IntCounterVec,
+    /// Failed `ProxiedStorage` requests to the upstream store by operation (plugins, artifact, image)
+    pub upstream_errors: IntCounterVec,
+    /// Successful artifact downloads by plugin and version, for versions in the plugin list
+    pub downloads: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("not_decky".to_owned()), None)
+            .expect("Invalid metrics registry");
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["route", "status"],
+        ).expect("Invalid requests metric");
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request handling time"),
+            &["route"],
+        ).expect("Invalid request_duration metric");
+        let bytes_served = IntCounterVec::new(
+            Opts::new("http_response_bytes_total", "HTTP response body bytes sent"),
+            &["route"],
+        ).expect("Invalid bytes_served metric");
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Storage cache lookups"),
+            &["cache", "result"],
+        ).expect("Invalid cache_lookups metric");
+        let upstream_errors = IntCounterVec::new(
+            Opts::new("upstream_errors_total", "Failed requests to the upstream store"),
+            &["operation"],
+        ).expect("Invalid upstream_errors metric");
+        let downloads = IntCounterVec::new(
+            Opts::new("downloads_total", "Artifact downloads"),
+            &["plugin", "version"],
+        ).expect("Invalid downloads metric");
+        registry.register(Box::new(requests.clone())).expect("Failed to register requests metric");
+        registry.register(Box::new(request_duration.clone())).expect("Failed to register request_duration metric");
+        registry.register(Box::new(bytes_served.clone())).expect("Failed to register bytes_served metric");
+        registry.register(Box::new(cache_lookups.clone())).expect("Failed to register cache_lookups metric");
+        registry.register(Box::new(upstream_errors.clone())).expect("Failed to register upstream_errors metric");
+        registry.register(Box::new(downloads.clone())).expect("Failed to register downloads metric");
+        Self {
+            registry,
+            requests,
+            request_duration,
+            bytes_served,
+            cache_lookups,
+            upstream_errors,
+            downloads,
+        }
+    }
+
+    /// Record a handled HTTP request. `route` must be a route pattern, not the request path, to keep label cardinality low.
+    pub fn observe_request(&self, route: &str, status: u16, duration: Duration, bytes: Option<u64>) {
+        self.requests.with_label_values(&[route, &status.to_string()]).inc();
+        self.request_duration.with_label_values(&[route]).observe(duration.as_secs_f64());
+        if let Some(bytes) = bytes {
+            self.bytes_served.with_label_values(&[route]).inc_by(bytes);
+        }
+    }
+
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        self.cache_lookups.with_label_values(&[cache, if hit { "hit" } else { "miss" }]).inc();
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+        metrics.observe_request("/plugins", 200, Duration::from_millis(5), Some(1234));
+        metrics.cache_lookup("plugins", true);
+        metrics.downloads.with_label_values(&["Example", "1.0.0"]).inc();
+        let text = metrics.render().expect("Metrics render error");
+        assert!(text.contains("not_decky_http_requests_total{route=\"/plugins\",status=\"200\"} 1"));
+        assert!(text.contains("not_decky_http_response_bytes_total{route=\"/plugins\"} 1234"));
+        assert!(text.contains("not_decky_cache_lookups_total{cache=\"plugins\",result=\"hit\"} 1"));
+        assert!(text.contains("not_decky_downloads_total{plugin=\"Example\",version=\"1.0.0\"} 1"));
+    }
+}
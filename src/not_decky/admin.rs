@@ -0,0 +1,54 @@
+use actix_web::{delete, post, web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+
+use crate::storage::{IStorage, VersionUpdate};
+
+use super::ApiToken;
+use super::errors::storage_error;
+
+#[derive(Deserialize)]
+pub struct YankRequest {
+    reason: Option<String>,
+}
+
+async fn apply_update(data: web::Data<dyn IStorage>, path: web::Path<(String, String)>, update: VersionUpdate) -> actix_web::Result<HttpResponse> {
+    let (name, version) = path.into_inner();
+    data.update_version(&name, &version, update).await
+        .map_err(storage_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/plugins/{name}/{version}")]
+pub async fn decky_delete_version(data: web::Data<dyn IStorage>, token: web::Data<ApiToken>, req: HttpRequest, path: web::Path<(String, String)>) -> actix_web::Result<impl Responder> {
+    token.authorize(&req)?;
+    let (name, version) = path.into_inner();
+    data.delete_version(&name, &version).await
+        .map_err(storage_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/plugins/{name}/{version}/hide")]
+pub async fn decky_hide_version(data: web::Data<dyn IStorage>, token: web::Data<ApiToken>, req: HttpRequest, path: web::Path<(String, String)>) -> actix_web::Result<impl Responder> {
+    token.authorize(&req)?;
+    apply_update(data, path, VersionUpdate::Hide).await
+}
+
+#[post("/plugins/{name}/{version}/unhide")]
+pub async fn decky_unhide_version(data: web::Data<dyn IStorage>, token: web::Data<ApiToken>, req: HttpRequest, path: web::Path<(String, String)>) -> actix_web::Result<impl Responder> {
+    token.authorize(&req)?;
+    apply_update(data, path, VersionUpdate::Unhide).await
+}
+
+/// Accepts an optional JSON body like `{"reason": "breaks on SteamOS 3.5"}`
+#[post("/plugins/{name}/{version}/yank")]
+pub async fn decky_yank_version(data: web::Data<dyn IStorage>, token: web::Data<ApiToken>, req: HttpRequest, path: web::Path<(String, String)>, body: Option<web::Json<YankRequest>>) -> actix_web::Result<impl Responder> {
+    token.authorize(&req)?;
+    let reason = body.and_then(|body| body.into_inner().reason);
+    apply_update(data, path, VersionUpdate::Yank(reason)).await
+}
+
+#[post("/plugins/{name}/{version}/unyank")]
+pub async fn decky_unyank_version(data: web::Data<dyn IStorage>, token: web::Data<ApiToken>, req: HttpRequest, path: web::Path<(String, String)>) -> actix_web::Result<impl Responder> {
+    token.authorize(&req)?;
+    apply_update(data, path, VersionUpdate::Unyank).await
+}
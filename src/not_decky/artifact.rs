@@ -1,10 +1,144 @@
-use actix_web::{get, web, Responder};
+use actix_web::http::{header::{self, EntityTag}, StatusCode};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use futures_util::StreamExt;
 
-use crate::storage::IStorage;
+use crate::metrics::METRICS;
+use crate::storage::{FileStream, IStorage, StorageFile};
+
+use super::errors::storage_error;
+use super::files::{serve_file, zip_content_type};
+
+/// Whether a response starts a download, so resumed downloads and revalidations aren't counted again
+fn is_new_download(response: &HttpResponse) -> bool {
+    match response.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT => response.headers().get(header::CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .map(|range| range.starts_with("bytes 0-"))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// A download which is recorded once the artifact was served
+struct Download {
+    data: web::Data<dyn IStorage>,
+    name: String,
+    version: String,
+    hash: String,
+}
+
+impl Download {
+    async fn count(self) {
+        self.data.count_download(&self.name, &self.version, &self.hash).await;
+        // only listed versions get a metric, since the labels come from the request path
+        let listed = self.data.plugins().await
+            .map(|plugins| plugins.iter()
+                .any(|plugin| plugin.name == self.name && plugin.versions.iter().any(|version| version.name == self.version)))
+            .unwrap_or(false);
+        if listed {
+            METRICS.downloads.with_label_values(&[&self.name, &self.version]).inc();
+        }
+    }
+
+    /// Count the download once the whole stream was sent, since its hash is only checked at the end
+    fn count_when_finished(self, stream: FileStream) -> FileStream {
+        FileStream {
+            length: stream.length,
+            chunks: futures_util::stream::unfold((stream.chunks, Some(self)), |(mut chunks, download)| async move {
+                match chunks.next().await {
+                    Some(Ok(chunk)) => Some((Ok(chunk), (chunks, download))),
+                    Some(Err(e)) => Some((Err(e), (chunks, None))),
+                    None => {
+                        if let Some(download) = download {
+                            download.count().await;
+                        }
+                        None
+                    },
+                }
+            }).boxed(),
+        }
+    }
+}
 
 #[get("/plugins/{name}/{version}/{hash}.zip")]
-pub async fn decky_artifact(data: web::Data<Box<dyn IStorage>>, path: web::Path<(String, String, String)>) -> actix_web::Result<impl Responder> {
-    let zip = web::block(move || data.get_artifact(&path.0, &path.1, &path.2)).await
-        .map_err(|e| actix_web::error::ErrorNotFound(e.to_string()))?;
-    Ok(zip)
+pub async fn decky_artifact(req: HttpRequest, data: web::Data<dyn IStorage>, path: web::Path<(String, String, String)>) -> actix_web::Result<impl Responder> {
+    let (name, version, hash) = path.into_inner();
+    let artifact = data.get_artifact(&name, &version, &hash).await
+        .map_err(storage_error)?;
+    // an artifact never changes for a given hash
+    let etag = EntityTag::new_strong(artifact.hash.clone());
+    let download = Download {
+        data,
+        name,
+        version,
+        hash: artifact.hash,
+    };
+    match artifact.file {
+        StorageFile::Stream(stream) => {
+            let file = StorageFile::Stream(download.count_when_finished(stream));
+            serve_file(&req, file, zip_content_type(), Some(etag)).await
+        },
+        file => {
+            let response = serve_file(&req, file, zip_content_type(), Some(etag)).await?;
+            if is_new_download(&response) {
+                download.count().await;
+            }
+            Ok(response)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use crate::storage::StorageError;
+    use crate::test_util::{plugin, StaticStorage};
+
+    #[test]
+    fn counted_downloads() {
+        assert!(is_new_download(&HttpResponse::Ok().finish()));
+        assert!(!is_new_download(&HttpResponse::NotModified().finish()));
+        let partial = |range: &str| HttpResponse::PartialContent()
+            .insert_header((header::CONTENT_RANGE, range.to_owned()))
+            .finish();
+        assert!(is_new_download(&partial("bytes 0-99/1000")));
+        assert!(!is_new_download(&partial("bytes 100-999/1000")));
+    }
+
+    #[actix_web::test]
+    async fn counted_streams() {
+        let store = Arc::new(StaticStorage::new(vec![plugin("Streamed", &[("1.0.0", "a")])]));
+        let data = web::Data::from(store.clone() as Arc<dyn IStorage>);
+        let download = |version: &str| Download {
+            data: data.clone(),
+            name: "Streamed".to_owned(),
+            version: version.to_owned(),
+            hash: "a".to_owned(),
+        };
+        let stream = |chunks: Vec<Result<bytes::Bytes, StorageError>>| FileStream {
+            length: None,
+            chunks: futures_util::stream::iter(chunks).boxed(),
+        };
+        let metric = || METRICS.downloads.with_label_values(&["Streamed", "1.0.0"]).get();
+
+        let mut counted = download("1.0.0").count_when_finished(stream(vec![Ok(bytes::Bytes::from_static(b"zip"))]));
+        assert!(counted.chunks.next().await.is_some());
+        assert_eq!(store.downloads.load(Ordering::SeqCst), 0);
+        assert!(counted.chunks.next().await.is_none());
+        assert_eq!(store.downloads.load(Ordering::SeqCst), 1);
+        assert_eq!(metric(), 1);
+
+        // a stream which fails, e.g. on a hash mismatch, isn't counted
+        let failed = download("1.0.0").count_when_finished(stream(vec![Err(StorageError::Upstream("Wrong hash".to_owned()))]));
+        assert_eq!(failed.chunks.count().await, 1);
+        assert_eq!(store.downloads.load(Ordering::SeqCst), 1);
+
+        // unlisted versions are counted by the store, but don't get a metric
+        download("0.1.0").count().await;
+        assert_eq!(store.downloads.load(Ordering::SeqCst), 2);
+        assert_eq!(metric(), 1);
+    }
 }
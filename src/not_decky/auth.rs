@@ -0,0 +1,29 @@
+use actix_web::{http::header, HttpRequest};
+
+/// Secret which privileged requests must present as `Authorization: Bearer <token>`
+pub struct ApiToken(pub Option<String>);
+
+impl ApiToken {
+    pub fn authorize(&self, req: &HttpRequest) -> actix_web::Result<()> {
+        let expected = self.0.as_ref()
+            .ok_or_else(|| actix_web::error::ErrorForbidden("No API token configured on this server"))?;
+        let provided = req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing API token"))?;
+        if constant_time_eq(expected.as_bytes(), provided.trim().as_bytes()) {
+            Ok(())
+        } else {
+            log::warn!("Rejected request to {} with bad API token", req.path());
+            Err(actix_web::error::ErrorUnauthorized("Invalid API token"))
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::{self, EntityTag, IfModifiedSince, IfNoneMatch, TryIntoHeaderPair};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+
+/// When each generated response body was first served, so it can be given a stable `Last-Modified`
+static FIRST_SEEN: LazyLock<Mutex<HashMap<String, SystemTime>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Forget old bodies once this many have been seen, which only costs clients one extra full response
+const MAX_FIRST_SEEN: usize = 256;
+
+/// Strong ETag of a response body
+pub fn body_etag(body: &[u8]) -> EntityTag {
+    EntityTag::new_strong(sha256::digest(body))
+}
+
+/// When a body with this ETag was first served
+pub fn first_seen(etag: &EntityTag) -> SystemTime {
+    let mut lock = FIRST_SEEN.lock().expect("Failed to acquire first seen lock");
+    if lock.len() >= MAX_FIRST_SEEN && !lock.contains_key(etag.tag()) {
+        lock.clear();
+    }
+    *lock.entry(etag.tag().to_owned()).or_insert_with(SystemTime::now)
+}
+
+/// Whether the client's copy is still current, by `If-None-Match` or otherwise `If-Modified-Since`
+pub fn is_fresh(req: &HttpRequest, etag: &EntityTag, last_modified: Option<SystemTime>) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => match (req.get_header::<IfModifiedSince>(), last_modified) {
+            (Some(IfModifiedSince(since)), Some(modified)) => unix_secs(modified) <= unix_secs(since.into()),
+            _ => false,
+        },
+    }
+}
+
+/// Add `ETag` and `Last-Modified` headers to an existing response
+pub fn set_validators(response: &mut HttpResponse, etag: EntityTag, last_modified: Option<SystemTime>) {
+    let headers = response.headers_mut();
+    if let Ok((name, value)) = header::ETag(etag).try_into_pair() {
+        headers.insert(name, value);
+    }
+    if let Some(Ok((name, value))) = last_modified.map(|modified| header::LastModified(modified.into()).try_into_pair()) {
+        headers.insert(name, value);
+    }
+}
+
+/// HTTP dates have a resolution of seconds
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+pub fn not_modified(etag: EntityTag, last_modified: Option<SystemTime>) -> HttpResponse {
+    let mut response = HttpResponse::NotModified();
+    response.insert_header(header::ETag(etag));
+    if let Some(last_modified) = last_modified {
+        response.insert_header(header::LastModified(last_modified.into()));
+    }
+    response.finish()
+}
+
+/// Serialize `value` as a JSON response with validators, or a 304 if the client already has it
+pub fn json_response<T: serde::Serialize>(req: &HttpRequest, value: &T) -> actix_web::Result<HttpResponse> {
+    let body = serde_json::to_vec(value)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let etag = body_etag(&body);
+    let last_modified = first_seen(&etag);
+    if is_fresh(req, &etag, Some(last_modified)) {
+        return Ok(not_modified(etag, Some(last_modified)));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(header::ContentType::json())
+        .insert_header(header::ETag(etag))
+        .insert_header(header::LastModified(last_modified.into()))
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn conditional_json() {
+        let value = vec!["PowerTools", "TabMaster"];
+        let response = json_response(&TestRequest::default().to_http_request(), &value).unwrap();
+        assert_eq!(response.status(), 200);
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+        let last_modified = response.headers().get(header::LAST_MODIFIED).unwrap().clone();
+
+        let req = TestRequest::default().insert_header((header::IF_NONE_MATCH, etag.clone())).to_http_request();
+        assert_eq!(json_response(&req, &value).unwrap().status(), 304);
+        let req = TestRequest::default().insert_header((header::IF_MODIFIED_SINCE, last_modified.clone())).to_http_request();
+        assert_eq!(json_response(&req, &value).unwrap().status(), 304);
+
+        // the ETag takes precedence over the date
+        let req = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .insert_header((header::IF_MODIFIED_SINCE, last_modified))
+            .to_http_request();
+        assert_eq!(json_response(&req, &vec!["PowerTools"]).unwrap().status(), 200);
+    }
+}
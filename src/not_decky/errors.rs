@@ -0,0 +1,58 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+use crate::storage::StorageError;
+
+#[derive(serde::Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    message: &'a str,
+}
+
+impl ResponseError for StorageError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Self::AlreadyExists(_) => StatusCode::CONFLICT,
+            Self::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Corrupt(_) | Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let message = self.to_string();
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.kind(),
+            message: &message,
+        })
+    }
+}
+
+/// Translate a storage failure into the matching HTTP error, with a JSON body describing it
+pub fn storage_error(e: StorageError) -> actix_web::Error {
+    if e.status_code().is_server_error() {
+        log::error!("Storage error: {}", e);
+    }
+    e.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+
+    #[test]
+    fn error_responses() {
+        let e: StorageError = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Permission denied").into();
+        assert_eq!(e.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(StorageError::Unavailable("Connection refused".to_owned()).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = StorageError::NotFound("Artifact PowerTools 1.0.0 does not exist".to_owned()).error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = response.into_body().try_into_bytes().unwrap();
+        assert_eq!(body, r#"{"error":"not_found","message":"Artifact PowerTools 1.0.0 does not exist"}"#);
+    }
+}
@@ -0,0 +1,115 @@
+use actix_files::{HttpRange, NamedFile};
+use actix_web::body::SizedStream;
+use actix_web::http::header::{self, ContentType, EntityTag};
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::storage::StorageFile;
+
+use super::conditional::{body_etag, first_seen, is_fresh, not_modified, set_validators};
+
+pub fn zip_content_type() -> ContentType {
+    ContentType("application/zip".parse().expect("Invalid zip mime type"))
+}
+
+/// Respond with a stored file, honouring `Range` and conditional requests.
+/// Without an `etag` one is derived from the file itself.
+pub async fn serve_file(req: &HttpRequest, file: StorageFile, content_type: ContentType, etag: Option<EntityTag>) -> actix_web::Result<HttpResponse> {
+    match file {
+        StorageFile::Path(path) => {
+            let named = NamedFile::open_async(path).await?
+                .set_content_type(content_type.0);
+            let etag = match etag {
+                Some(etag) => etag,
+                None => return Ok(named.into_response(req)),
+            };
+            let last_modified = named.modified();
+            if is_fresh(req, &etag, last_modified) {
+                return Ok(not_modified(etag, last_modified));
+            }
+            // NamedFile still handles the Last-Modified side and ranges
+            let mut response = named.use_etag(false).into_response(req);
+            set_validators(&mut response, etag, None);
+            Ok(response)
+        },
+        StorageFile::Bytes(bytes) => {
+            let etag = etag.unwrap_or_else(|| body_etag(&bytes));
+            let last_modified = Some(first_seen(&etag));
+            if is_fresh(req, &etag, last_modified) {
+                return Ok(not_modified(etag, last_modified));
+            }
+            let mut response = serve_bytes(req, bytes, content_type);
+            set_validators(&mut response, etag, last_modified);
+            Ok(response)
+        },
+        StorageFile::Stream(stream) => {
+            if let Some(etag) = &etag {
+                if is_fresh(req, etag, None) {
+                    return Ok(not_modified(etag.clone(), None));
+                }
+            }
+            // a stream can't skip ahead, so ranges are ignored and the whole file is sent
+            let mut builder = HttpResponse::Ok();
+            builder.insert_header(content_type);
+            let mut response = match stream.length {
+                Some(length) => builder.body(SizedStream::new(length, stream.chunks)),
+                None => builder.streaming(stream.chunks),
+            };
+            if let Some(etag) = etag {
+                set_validators(&mut response, etag, None);
+            }
+            Ok(response)
+        },
+    }
+}
+
+fn serve_bytes(req: &HttpRequest, bytes: bytes::Bytes, content_type: ContentType) -> HttpResponse {
+    let length = bytes.len() as u64;
+    let range = req.headers().get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(|range| HttpRange::parse(range, length));
+    match range {
+        // like NamedFile, only the first of multiple ranges is served
+        Some(Ok(ranges)) if !ranges.is_empty() => {
+            let range = ranges[0];
+            let end = range.start + range.length;
+            HttpResponse::PartialContent()
+                .insert_header(content_type)
+                .insert_header((header::ACCEPT_RANGES, "bytes"))
+                .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, end - 1, length)))
+                .body(bytes.slice(range.start as usize..end as usize))
+        },
+        Some(Err(_)) => HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", length)))
+            .finish(),
+        _ => HttpResponse::Ok()
+            .insert_header(content_type)
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .body(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn range_requests() {
+        let data = bytes::Bytes::from_static(b"0123456789");
+
+        let req = TestRequest::default().to_http_request();
+        let response = serve_bytes(&req, data.clone(), zip_content_type());
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(header::ACCEPT_RANGES).unwrap(), "bytes");
+
+        let req = TestRequest::default().insert_header((header::RANGE, "bytes=2-5")).to_http_request();
+        let response = serve_bytes(&req, data.clone(), zip_content_type());
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 2-5/10");
+        assert_eq!(response.into_body().try_into_bytes().unwrap(), bytes::Bytes::from_static(b"2345"));
+
+        let req = TestRequest::default().insert_header((header::RANGE, "bytes=20-")).to_http_request();
+        assert_eq!(serve_bytes(&req, data, zip_content_type()).status(), 416);
+    }
+}
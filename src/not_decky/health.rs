@@ -0,0 +1,13 @@
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::storage::{HealthStatus, IStorage};
+
+#[get("/health")]
+pub async fn decky_health(data: web::Data<dyn IStorage>) -> actix_web::Result<impl Responder> {
+    let health = data.health().await;
+    let mut response = match health.status {
+        HealthStatus::Healthy | HealthStatus::Degraded => HttpResponse::Ok(),
+        HealthStatus::Failing => HttpResponse::ServiceUnavailable(),
+    };
+    Ok(response.json(health))
+}
@@ -1,10 +1,14 @@
-use actix_web::{get, web, Responder};
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpRequest, Responder};
 
 use crate::storage::IStorage;
 
+use super::errors::storage_error;
+use super::files::serve_file;
+
 #[get("/plugins/{name}.png")]
-pub async fn decky_image(data: web::Data<Box<dyn IStorage>>, path: web::Path<String>) -> actix_web::Result<impl Responder> {
-    let zip = web::block(move || data.get_image(&path)).await
-        .map_err(|e| actix_web::error::ErrorNotFound(e.to_string()))?;
-    Ok(zip)
+pub async fn decky_image(req: HttpRequest, data: web::Data<dyn IStorage>, path: web::Path<String>) -> actix_web::Result<impl Responder> {
+    let image = data.get_image(&path).await
+        .map_err(storage_error)?;
+    serve_file(&req, image, ContentType::png(), None).await
 }
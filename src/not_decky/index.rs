@@ -1,6 +1,144 @@
-use actix_web::{get, HttpResponse, Responder};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use decky_api::{StorePlugin, StorePluginList, StorePluginVersion};
+
+use actix_web::{get, http::header::ContentType, web, HttpResponse, Responder};
+
+use crate::consts;
+use crate::storage::IStorage;
+
+use super::errors::storage_error;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:0 auto;max-width:960px;padding:1em;background:#1b2838;color:#c7d5e0}\
+a{color:#66c0f4}\
+.plugin{display:flex;gap:1em;padding:1em 0;border-bottom:1px solid #2a475e}\
+.plugin img{width:240px;height:150px;object-fit:cover;flex-shrink:0;background:#2a475e}\
+.plugin h2{margin:0}\
+.author,.downloads{color:#8f98a0}\
+.tag{display:inline-block;margin:0 .3em .3em 0;padding:0 .4em;border-radius:.3em;background:#2a475e}\
+.yanked{text-decoration:line-through}";
+
+/// Escape text for use in HTML element content and quoted attribute values
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn plugin_downloads(plugin: &StorePlugin, statistics: &HashMap<String, u64>) -> Option<u64> {
+    statistics.get(&plugin.name).copied().or(plugin.downloads)
+}
+
+fn version_downloads(plugin: &StorePlugin, version: &StorePluginVersion, statistics: &HashMap<String, u64>) -> Option<u64> {
+    // FileStorage statistics are keyed by "<plugin> <version>" for individual versions
+    statistics.get(&format!("{} {}", plugin.name, version.name)).copied().or(version.downloads)
+}
+
+fn render_version(html: &mut String, plugin: &StorePlugin, version: &StorePluginVersion, statistics: &HashMap<String, u64>) {
+    html.push_str("<li");
+    if version.yanked {
+        html.push_str(" class=\"yanked\"");
+        if let Some(reason) = &version.yanked_reason {
+            write!(html, " title=\"Yanked: {}\"", escape(reason)).unwrap();
+        }
+    }
+    html.push('>');
+    match &version.artifact {
+        Some(artifact) => write!(html, "<a href=\"{}\">{}</a>", escape(artifact), escape(&version.name)).unwrap(),
+        None => html.push_str(&escape(&version.name)),
+    }
+    if let Some(downloads) = version_downloads(plugin, version, statistics) {
+        write!(html, " <span class=\"downloads\">({} downloads)</span>", downloads).unwrap();
+    }
+    html.push_str("</li>");
+}
+
+fn render_plugin(html: &mut String, plugin: &StorePlugin, statistics: &HashMap<String, u64>) {
+    write!(html, "<div class=\"plugin\" id=\"{}\">", escape(&plugin.name)).unwrap();
+    write!(html, "<img src=\"{}\" alt=\"\" loading=\"lazy\">", escape(&plugin.image_url)).unwrap();
+    html.push_str("<div>");
+    write!(html, "<h2>{}</h2>", escape(&plugin.name)).unwrap();
+    write!(html, "<div class=\"author\">by {}", escape(&plugin.author)).unwrap();
+    if let Some(downloads) = plugin_downloads(plugin, statistics) {
+        write!(html, " &middot; {} downloads", downloads).unwrap();
+    }
+    html.push_str("</div>");
+    write!(html, "<p>{}</p>", escape(&plugin.description)).unwrap();
+    if !plugin.tags.is_empty() {
+        html.push_str("<div>");
+        for tag in &plugin.tags {
+            write!(html, "<span class=\"tag\">{}</span>", escape(tag)).unwrap();
+        }
+        html.push_str("</div>");
+    }
+    html.push_str("<ul>");
+    for version in &plugin.versions {
+        render_version(html, plugin, version, statistics);
+    }
+    html.push_str("</ul></div></div>");
+}
+
+/// Render the store catalogue as a standalone HTML page
+fn render_index(mut plugins: StorePluginList, statistics: &HashMap<String, u64>) -> String {
+    plugins.sort_by_key(|plugin| plugin.name.to_lowercase());
+    let mut html = String::with_capacity(1024 + plugins.len() * 1024);
+    write!(
+        html,
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{0}</title><style>{1}</style></head><body><h1>{0}</h1><p>{2} plugins</p>",
+        escape(consts::PACKAGE_NAME), STYLE, plugins.len(),
+    ).unwrap();
+    for plugin in &plugins {
+        render_plugin(&mut html, plugin, statistics);
+    }
+    write!(html, "<footer><p>{} v{}</p></footer></body></html>", escape(consts::PACKAGE_NAME), consts::PACKAGE_VERSION).unwrap();
+    html
+}
 
 #[get("/")]
-pub async fn decky_index() -> impl Responder {
-    HttpResponse::Ok().body("TODO")
+pub async fn decky_index(data: web::Data<dyn IStorage>) -> actix_web::Result<impl Responder> {
+    let (plugins, statistics) = futures_util::future::join(data.plugins(), data.get_statistics()).await;
+    // download counts are only decoration here
+    let statistics = statistics.unwrap_or_else(|e| {
+        log::warn!("Rendering index without statistics: {}", e);
+        HashMap::with_capacity(0)
+    });
+    let html = render_index(plugins.map_err(storage_error)?, &statistics);
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(html))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_catalogue() {
+        let plugins = vec![StorePlugin {
+            name: "<Example>".to_owned(),
+            author: "Jane & John".to_owned(),
+            tags: vec!["root".to_owned()],
+            versions: vec![StorePluginVersion {
+                name: "1.0.0".to_owned(),
+                hash: "abc".to_owned(),
+                artifact: Some("http://localhost/plugins/Example/1.0.0/abc.zip".to_owned()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }];
+        let statistics: HashMap<String, u64> = [("<Example> 1.0.0".to_owned(), 42)].into_iter().collect();
+        let html = render_index(plugins, &statistics);
+        assert!(html.contains("<h2>&lt;Example&gt;</h2>"));
+        assert!(html.contains("by Jane &amp; John"));
+        assert!(html.contains("<span class=\"tag\">root</span>"));
+        assert!(html.contains("<a href=\"http://localhost/plugins/Example/1.0.0/abc.zip\">1.0.0</a> <span class=\"downloads\">(42 downloads)</span>"));
+    }
 }
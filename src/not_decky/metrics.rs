@@ -0,0 +1,12 @@
+use actix_web::{get, HttpResponse, Responder};
+
+use crate::metrics::METRICS;
+
+#[get("/metrics")]
+pub async fn decky_metrics() -> actix_web::Result<impl Responder> {
+    let text = METRICS.render()
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(text))
+}
@@ -1,11 +1,24 @@
+mod admin;
 mod artifact;
+mod auth;
+mod conditional;
+mod errors;
+mod files;
+mod health;
 mod image;
 mod index;
+mod metrics;
 mod plugins;
+mod publish;
 mod stats;
 
+pub use admin::{decky_delete_version, decky_hide_version, decky_unhide_version, decky_yank_version, decky_unyank_version};
 pub use artifact::decky_artifact;
+pub use auth::ApiToken;
+pub use health::decky_health;
 pub use image::decky_image;
 pub use index::decky_index;
+pub use metrics::decky_metrics;
 pub use plugins::decky_plugins;
+pub use publish::decky_publish;
 pub use stats::decky_statistics;
@@ -1,11 +1,184 @@
-use decky_api::StorePluginList;
+use std::collections::HashMap;
 
-use actix_web::{get, web, Responder};
+use decky_api::{StorePlugin, StorePluginList};
+
+use actix_web::{get, web, HttpRequest, Responder};
 
 use crate::storage::IStorage;
 
+use super::conditional::json_response;
+use super::errors::storage_error;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SortBy {
+    Name,
+    Date,
+    Downloads,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SortDirection {
+    Asc,
+    Desc,
+}
+
+/// Upstream-compatible `/plugins` query string, e.g. `?query=tab&tags=root,qam&sort_by=downloads&sort_direction=asc`
+#[derive(Debug)]
+struct PluginsQuery {
+    query: String,
+    tags: Vec<String>,
+    include_hidden: bool,
+    sort_by: Option<SortBy>,
+    sort_direction: SortDirection,
+}
+
+impl PluginsQuery {
+    /// Parse from key-value pairs, since `tags` may be repeated as well as comma-separated
+    fn from_pairs(pairs: &[(String, String)]) -> actix_web::Result<Self> {
+        let mut result = Self {
+            query: String::new(),
+            tags: Vec::new(),
+            include_hidden: false,
+            sort_by: None,
+            sort_direction: SortDirection::Desc,
+        };
+        for (key, value) in pairs {
+            match key as &str {
+                "query" => result.query = value.trim().to_lowercase(),
+                "tags" => result.tags.extend(
+                    value.split(',')
+                        .map(|tag| tag.trim())
+                        .filter(|tag| !tag.is_empty())
+                        .map(|tag| tag.to_owned())
+                ),
+                "sort_by" => result.sort_by = match value as &str {
+                    "name" => Some(SortBy::Name),
+                    "date" => Some(SortBy::Date),
+                    "downloads" => Some(SortBy::Downloads),
+                    "" => None,
+                    other => return Err(actix_web::error::ErrorBadRequest(format!("Unknown sort_by `{}`, expected name, date or downloads", other))),
+                },
+                "sort_direction" => result.sort_direction = match value as &str {
+                    "asc" => SortDirection::Asc,
+                    "desc" => SortDirection::Desc,
+                    other => return Err(actix_web::error::ErrorBadRequest(format!("Unknown sort_direction `{}`, expected asc or desc", other))),
+                },
+                "hidden" => result.include_hidden = value == "true" || value == "1",
+                _ => log::debug!("Ignoring /plugins query parameter {}", key),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Whether download counts must come from `IStorage::get_statistics`
+    fn needs_statistics(&self, plugins: &StorePluginList) -> bool {
+        self.sort_by == Some(SortBy::Downloads) && plugins.iter().any(|plugin| plugin.downloads.is_none())
+    }
+
+    fn matches(&self, plugin: &StorePlugin) -> bool {
+        (self.include_hidden || plugin.visible)
+            && (self.query.is_empty() || plugin.name.to_lowercase().contains(&self.query))
+            && self.tags.iter().all(|tag| plugin.tags.contains(tag))
+    }
+
+    fn apply(&self, mut plugins: StorePluginList, statistics: &HashMap<String, u64>) -> StorePluginList {
+        plugins.retain(|plugin| self.matches(plugin));
+        match self.sort_by {
+            // upstream lists by id when no order is requested
+            None => plugins.sort_by(|a, b| a.id.cmp(&b.id).then_with(|| a.name.cmp(&b.name))),
+            Some(sort_by) => {
+                plugins.sort_by(|a, b| {
+                    let ordering = match sort_by {
+                        SortBy::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+                        SortBy::Date => created_of(a).cmp(&created_of(b))
+                            // ids are handed out in order of creation
+                            .then_with(|| a.id.cmp(&b.id)),
+                        SortBy::Downloads => downloads_of(a, statistics).cmp(&downloads_of(b, statistics)),
+                    };
+                    match self.sort_direction {
+                        SortDirection::Asc => ordering,
+                        SortDirection::Desc => ordering.reverse(),
+                    }.then_with(|| a.name.cmp(&b.name))
+                });
+            }
+        }
+        plugins
+    }
+}
+
+fn created_of(plugin: &StorePlugin) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    plugin.created.as_ref()
+        .and_then(|created| chrono::DateTime::parse_from_rfc3339(created).ok())
+}
+
+fn downloads_of(plugin: &StorePlugin, statistics: &HashMap<String, u64>) -> u64 {
+    plugin.downloads
+        .or_else(|| statistics.get(&plugin.name).copied())
+        .unwrap_or(0)
+}
+
 #[get("/plugins")]
-pub async fn decky_plugins(data: actix_web::web::Data<Box<dyn IStorage>>) -> impl Responder {
-    let plugins: StorePluginList = web::block(move || data.plugins()).await.unwrap();
-    web::Json(plugins)
+pub async fn decky_plugins(req: HttpRequest, data: web::Data<dyn IStorage>, query: web::Query<Vec<(String, String)>>) -> actix_web::Result<impl Responder> {
+    let query = PluginsQuery::from_pairs(&query)?;
+    let plugins = data.plugins().await.map_err(storage_error)?;
+    let statistics = if query.needs_statistics(&plugins) {
+        data.get_statistics().await.map_err(storage_error)?
+    } else {
+        HashMap::with_capacity(0)
+    };
+    let plugins: StorePluginList = query.apply(plugins, &statistics);
+    json_response(&req, &plugins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn plugin(id: usize, name: &str, tags: &[&str]) -> StorePlugin {
+        StorePlugin {
+            id,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..test_util::plugin(name, &[])
+        }
+    }
+
+    fn names(plugins: &StorePluginList) -> Vec<&str> {
+        plugins.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn filter_and_sort_plugins() {
+        let plugins = vec![
+            plugin(3, "TabMaster", &["root", "qam"]),
+            plugin(1, "PowerTools", &["root"]),
+            plugin(2, "Tab Tools", &["qam"]),
+        ];
+        let stats: HashMap<String, u64> = [("PowerTools".to_owned(), 10), ("TabMaster".to_owned(), 5)].into_iter().collect();
+
+        let query = PluginsQuery::from_pairs(&[]).unwrap();
+        assert_eq!(names(&query.apply(plugins.clone(), &stats)), vec!["PowerTools", "Tab Tools", "TabMaster"]);
+
+        let pairs = vec![("query".to_owned(), "TAB".to_owned()), ("tags".to_owned(), "qam".to_owned()), ("sort_by".to_owned(), "name".to_owned())];
+        let query = PluginsQuery::from_pairs(&pairs).unwrap();
+        assert_eq!(names(&query.apply(plugins.clone(), &stats)), vec!["TabMaster", "Tab Tools"]);
+
+        let pairs = vec![("tags".to_owned(), "root,qam".to_owned())];
+        let query = PluginsQuery::from_pairs(&pairs).unwrap();
+        assert_eq!(names(&query.apply(plugins.clone(), &stats)), vec!["TabMaster"]);
+
+        let pairs = vec![("sort_by".to_owned(), "downloads".to_owned()), ("sort_direction".to_owned(), "asc".to_owned())];
+        let query = PluginsQuery::from_pairs(&pairs).unwrap();
+        assert_eq!(names(&query.apply(plugins.clone(), &stats)), vec!["Tab Tools", "TabMaster", "PowerTools"]);
+
+        let mut plugins = plugins;
+        plugins[0].created = Some("2023-01-01T00:00:00+00:00".to_owned());
+        plugins[1].created = Some("2022-06-01T12:00:00-04:00".to_owned());
+        plugins[2].visible = false;
+        let pairs = vec![("sort_by".to_owned(), "date".to_owned())];
+        let query = PluginsQuery::from_pairs(&pairs).unwrap();
+        assert_eq!(names(&query.apply(plugins, &stats)), vec!["TabMaster", "PowerTools"]);
+
+        assert!(PluginsQuery::from_pairs(&[("sort_by".to_owned(), "stars".to_owned())]).is_err());
+    }
 }
@@ -0,0 +1,105 @@
+use actix_multipart::Multipart;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use bytes::{Bytes, BytesMut};
+use futures_util::TryStreamExt;
+
+use crate::storage::{IStorage, PluginUpload};
+
+use super::ApiToken;
+use super::errors::storage_error;
+
+const MAX_FIELD_SIZE: usize = 512 * 1024 * 1024;
+
+/// Multipart form with an `artifact` zip and optional `metadata` (plugin.json) and `image` (png) fields
+#[post("/plugins/{name}/{version}")]
+pub async fn decky_publish(
+    data: web::Data<dyn IStorage>,
+    token: web::Data<ApiToken>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    mut payload: Multipart,
+) -> actix_web::Result<impl Responder> {
+    token.authorize(&req)?;
+    let mut artifact = None;
+    let mut metadata = None;
+    let mut image = None;
+    while let Some(mut field) = payload.try_next().await? {
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = field.try_next().await? {
+            if buffer.len() + chunk.len() > MAX_FIELD_SIZE {
+                return Err(actix_web::error::ErrorPayloadTooLarge(format!("Field {} is too large", field.name())));
+            }
+            buffer.extend_from_slice(&chunk);
+        }
+        let value: Bytes = buffer.freeze();
+        match field.name() {
+            "artifact" => artifact = Some(value),
+            "metadata" => metadata = Some(value),
+            "image" => image = Some(value),
+            other => return Err(actix_web::error::ErrorBadRequest(format!("Unexpected field {}", other))),
+        }
+    }
+    let upload = PluginUpload {
+        artifact: artifact.ok_or_else(|| actix_web::error::ErrorBadRequest("Missing artifact field"))?,
+        metadata,
+        image,
+    };
+    let (name, version) = path.into_inner();
+    let published = data.publish(&name, &version, upload).await
+        .map_err(storage_error)?;
+    Ok(HttpResponse::Created().json(published))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use actix_web::{http::header, test, App};
+    use decky_api::StorePluginVersion;
+    use crate::storage::FileStorage;
+    use crate::test_util::{self, TempDir};
+
+    const BOUNDARY: &str = "not-decky-test-boundary";
+
+    fn multipart_body(fields: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n", BOUNDARY, name).as_bytes());
+            body.extend_from_slice(value);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    #[actix_web::test]
+    async fn publish_with_token() {
+        let dir = TempDir::new("publish-route");
+        let storage: Arc<dyn IStorage> = Arc::new(FileStorage::new(dir.join("store"), "http://localhost:22252".to_owned(), false));
+        let app = test::init_service(App::new()
+            .app_data(web::Data::from(storage))
+            .app_data(web::Data::new(ApiToken(Some("secret".to_owned()))))
+            .service(decky_publish)
+        ).await;
+        let artifact = test_util::zip(&[("Example/plugin.json", r#"{"name": "Example", "author": "Jane"}"#)]);
+        let body = multipart_body(&[("artifact", &artifact)]);
+        let request = |token: Option<&str>| {
+            let mut request = test::TestRequest::post()
+                .uri("/plugins/Example/1.0.0")
+                .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+                .set_payload(body.clone());
+            if let Some(token) = token {
+                request = request.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+            }
+            request.to_request()
+        };
+
+        assert_eq!(test::call_service(&app, request(None)).await.status(), 401);
+        assert_eq!(test::call_service(&app, request(Some("wrong"))).await.status(), 401);
+        let response = test::call_service(&app, request(Some("secret"))).await;
+        assert_eq!(response.status(), 201);
+        let published: StorePluginVersion = test::read_body_json(response).await;
+        assert_eq!(published.hash, sha256::digest(artifact.as_slice()));
+        assert_eq!(test::call_service(&app, request(Some("secret"))).await.status(), 409);
+    }
+}
@@ -1,12 +1,18 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-use actix_web::{get, web, Responder};
+use actix_web::{get, web, HttpRequest, Responder};
 
 use crate::storage::IStorage;
 
+use super::conditional::json_response;
+use super::errors::storage_error;
+
 #[get("/stats")]
-pub async fn decky_statistics(data: actix_web::web::Data<Box<dyn IStorage>>) -> impl Responder {
-    println!("stats");
-    let plugins: HashMap<String, u64> = data.get_statistics();
-    web::Json(plugins)
+pub async fn decky_statistics(req: HttpRequest, data: web::Data<dyn IStorage>) -> actix_web::Result<impl Responder> {
+    // sorted, so that unchanged statistics keep the same ETag
+    let plugins: BTreeMap<String, u64> = data.get_statistics().await
+        .map_err(storage_error)?
+        .into_iter()
+        .collect();
+    json_response(&req, &plugins)
 }
@@ -1,102 +1,488 @@
-use std::sync::{RwLock, atomic::{AtomicI64, Ordering}};
+use std::sync::{Arc, RwLock, atomic::{AtomicBool, AtomicI64, Ordering}};
+use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
+use async_trait::async_trait;
 use decky_api::StorePluginList;
-use bytes::Bytes;
 use chrono::Utc;
 
-use super::IStorage;
+use crate::metrics::METRICS;
+
+use super::{Artifact, IStorage, PluginUpload, StorageError, StorageFile, StoreHealth, VersionUpdate};
+
+/// Values which can be cached, where an empty value from a refresh is treated as suspicious
+trait CacheValue: Clone + Default + 'static {
+    fn is_empty(&self) -> bool;
+}
+
+impl<V: Clone + 'static> CacheValue for Vec<V> {
+    fn is_empty(&self) -> bool {
+        Vec::is_empty(self)
+    }
+}
+
+impl<K: Clone + 'static, V: Clone + 'static> CacheValue for HashMap<K, V> {
+    fn is_empty(&self) -> bool {
+        HashMap::is_empty(self)
+    }
+}
 
 struct Cached<T: Clone> {
+    /// Metrics label, or `None` for maps which count lookups per entry instead
+    name: Option<&'static str>,
     expiry: AtomicI64,
     value: RwLock<T>,
-    ttl: i64
+    ttl: i64,
+    /// Whether `value` was fetched at least once
+    filled: AtomicBool,
+    /// Set while a background refresh is running, so only one runs at a time
+    refreshing: AtomicBool,
+    /// Held while `value` is fetched for the first time, so concurrent requests wait for that fetch
+    first_fill: tokio::sync::Mutex<()>,
+}
+
+/// Clears `refreshing` once a background refresh ends, even if it panicked
+struct RefreshGuard<T: CacheValue>(Arc<Cached<T>>);
+
+impl<T: CacheValue> Drop for RefreshGuard<T> {
+    fn drop(&mut self) {
+        self.0.refreshing.store(false, Ordering::Release);
+    }
 }
 
-impl<T: Clone> Cached<T> {
-    fn new(value: T, duration: i64) -> Self {
+impl<T: CacheValue> Cached<T> {
+    /// An empty cache, which is filled in on first use
+    fn new(name: Option<&'static str>, duration: i64) -> Self {
         Self {
-            expiry: AtomicI64::new(Utc::now().timestamp() + duration),
-            value: RwLock::new(value),
+            name,
+            expiry: AtomicI64::new(0),
+            value: RwLock::new(T::default()),
             ttl: duration,
+            filled: AtomicBool::new(false),
+            refreshing: AtomicBool::new(false),
+            first_fill: tokio::sync::Mutex::new(()),
         }
     }
 
-    fn get<F: FnOnce() -> T>(&self, getter: F) -> T {
-        let now = Utc::now().timestamp();
-        let expiry = self.expiry.load(Ordering::Acquire);
-        if expiry < now {
-            // refresh required
-            let new_value = getter();
-            let new_expiry = now + self.ttl;
-            let mut write_lock = self.value.write().expect("Failed to acquire cache write lock");
-            self.expiry.store(new_expiry, Ordering::Release);
-            *write_lock = new_value.clone();
-            new_value
-        } else {
-            // cache is still good
-            self.value.read().expect("Failed to acquire cache read lock").clone()
+    /// Get the cached value, fetching it the first time. Errors are not cached.
+    /// Once expired, the stale value is still returned while a single background task fetches a new one.
+    async fn get<F, Fut>(self: &Arc<Self>, fetch: F) -> Result<T, StorageError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, StorageError>> + 'static,
+    {
+        if !self.filled.load(Ordering::Acquire) {
+            let _fill = self.first_fill.lock().await;
+            // another request may have filled it while this one waited
+            if !self.filled.load(Ordering::Acquire) {
+                if let Some(name) = self.name {
+                    METRICS.cache_lookup(name, false);
+                }
+                let new_value = fetch().await?;
+                self.refresh(new_value.clone());
+                return Ok(new_value);
+            }
+        }
+        let expired = self.expiry.load(Ordering::Acquire) < Utc::now().timestamp();
+        if expired && self.refreshing.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            let guard = RefreshGuard(self.clone());
+            let update = fetch();
+            actix_web::rt::spawn(async move {
+                let result = update.await;
+                guard.0.revalidated(result);
+            });
         }
+        if let Some(name) = self.name {
+            METRICS.cache_lookup(name, true);
+        }
+        Ok(self.value.read().expect("Failed to acquire cache read lock").clone())
+    }
+
+    /// Store the result of a background refresh, keeping the last good value if it failed or came back empty
+    fn revalidated(&self, result: Result<T, StorageError>) {
+        let name = self.name.unwrap_or("files");
+        match result {
+            Ok(new_value) if !new_value.is_empty() => self.refresh(new_value),
+            Ok(new_value) if self.value.read().expect("Failed to acquire cache read lock").is_empty() => self.refresh(new_value),
+            Ok(_) => {
+                log::warn!("Refreshed {} cache came back empty, keeping the previous value", name);
+                self.extend();
+            },
+            Err(e) => {
+                log::warn!("Failed to refresh {} cache, keeping the previous value: {}", name, e);
+                self.extend();
+            },
+        }
+    }
+
+    /// Fetch the value again on next use, serving the current one in the meantime
+    fn invalidate(&self) {
+        self.expiry.store(0, Ordering::Release);
+    }
+
+    /// Keep the current value for another period before trying again
+    fn extend(&self) {
+        self.expiry.store(Utc::now().timestamp() + self.ttl, Ordering::Release);
     }
 
     fn refresh(&self, new_value: T) {
         let new_expiry = Utc::now().timestamp() + self.ttl;
         let mut write_lock = self.value.write().expect("Failed to acquire cache write lock");
         self.expiry.store(new_expiry, Ordering::Release);
+        self.filled.store(true, Ordering::Release);
         *write_lock = new_value;
     }
 }
 
-pub struct CachedStorage<S: AsRef<dyn IStorage> + Send + Sync> {
-    fallback: S,
-    plugins_cache: Cached<StorePluginList>,
-    statistics_cache: Cached<HashMap<String, u64>>,
-    artifacts_cache: Cached<HashMap<String, Bytes>>,
-    images_cache: Cached<HashMap<String, Bytes>>,
+impl<K: Eq + Hash + Clone + 'static, V: Clone + 'static> Cached<HashMap<K, V>> {
+    /// Get a single file, forgetting all files once they expire
+    fn get_entry<Q: Eq + Hash + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        if self.expiry.load(Ordering::Acquire) < Utc::now().timestamp() {
+            self.refresh(HashMap::new());
+            return None;
+        }
+        self.value.read().expect("Failed to acquire cache read lock").get(key).cloned()
+    }
+
+    /// Add a file without extending the lifetime of the others
+    fn insert_entry(&self, key: K, file: V) {
+        self.value.write().expect("Failed to acquire cache write lock").insert(key, file);
+    }
+}
+
+/// A file which can be served more than once, unlike a stream
+#[derive(Clone)]
+enum CachedFile {
+    Path(PathBuf),
+    Bytes(bytes::Bytes),
+}
+
+impl CachedFile {
+    fn of(file: &StorageFile) -> Option<Self> {
+        match file {
+            StorageFile::Path(path) => Some(Self::Path(path.clone())),
+            StorageFile::Bytes(bytes) => Some(Self::Bytes(bytes.clone())),
+            StorageFile::Stream(_) => None,
+        }
+    }
 }
 
-impl<S: AsRef<dyn IStorage> + Send + Sync> CachedStorage<S> {
+/// An artifact along with the modification time of its file, so a file replaced on disk is checked again
+#[derive(Clone)]
+struct CachedArtifact {
+    file: CachedFile,
+    modified: Option<SystemTime>,
+}
+
+impl CachedArtifact {
+    async fn new(file: CachedFile) -> Option<Self> {
+        let modified = match &file {
+            CachedFile::Path(path) => Some(modified(path).await?),
+            CachedFile::Bytes(_) => None,
+        };
+        Some(Self { file, modified })
+    }
+
+    /// Whether the file is still the one which the inner store checked
+    async fn is_current(&self) -> bool {
+        match &self.file {
+            CachedFile::Path(path) => modified(path).await == self.modified,
+            CachedFile::Bytes(_) => true,
+        }
+    }
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+impl From<CachedFile> for StorageFile {
+    fn from(file: CachedFile) -> Self {
+        match file {
+            CachedFile::Path(path) => Self::Path(path),
+            CachedFile::Bytes(bytes) => Self::Bytes(bytes),
+        }
+    }
+}
+
+pub struct CachedStorage<S: AsRef<dyn IStorage> + Send + Sync + 'static> {
+    fallback: Arc<S>,
+    plugins_cache: Arc<Cached<StorePluginList>>,
+    statistics_cache: Arc<Cached<HashMap<String, u64>>>,
+    /// Artifacts by `(name, version, hash)`, as checked by the inner store
+    artifacts_cache: Cached<HashMap<(String, String, String), CachedArtifact>>,
+    images_cache: Cached<HashMap<String, CachedFile>>,
+}
+
+impl<S: AsRef<dyn IStorage> + Send + Sync + 'static> CachedStorage<S> {
     pub fn new(duration: i64, inner: S) -> Self {
         Self {
-            plugins_cache: Cached::new(inner.as_ref().plugins(), duration),
-            statistics_cache: Cached::new(inner.as_ref().get_statistics(), duration),
-            artifacts_cache: Cached::new(HashMap::new(), duration),
-            images_cache: Cached::new(HashMap::new(), duration),
-            fallback: inner,
+            plugins_cache: Arc::new(Cached::new(Some("plugins"), duration)),
+            statistics_cache: Arc::new(Cached::new(Some("statistics"), duration)),
+            artifacts_cache: Cached::new(None, duration),
+            images_cache: Cached::new(None, duration),
+            fallback: Arc::new(inner),
         }
     }
 }
 
-impl<S: AsRef<dyn IStorage> + Send + Sync> IStorage for CachedStorage<S> {
-    fn plugins(&self) -> StorePluginList {
-        self.plugins_cache.get(|| self.fallback.as_ref().plugins())
+impl<S: AsRef<dyn IStorage> + Send + Sync + 'static> CachedStorage<S> {
+    fn inner(&self) -> &dyn IStorage {
+        (*self.fallback).as_ref()
     }
 
-    fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<bytes::Bytes, std::io::Error> {
-        let mut cached = self.artifacts_cache.get(|| HashMap::new());
-        if let Some(bytes) = cached.get(hash) {
-            Ok(bytes.to_owned())
-        } else {
-            let new_artifact = self.fallback.as_ref().get_artifact(name, version, hash)?;
-            cached.insert(hash.to_owned(), new_artifact.clone());
-            self.artifacts_cache.refresh(cached);
-            Ok(new_artifact)
+    /// Make changes visible immediately after modifying the inner store
+    async fn reload_plugins(&self) {
+        match self.inner().plugins().await {
+            Ok(plugins) => self.plugins_cache.refresh(plugins),
+            Err(_) => self.plugins_cache.invalidate(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: AsRef<dyn IStorage> + Send + Sync + 'static> IStorage for CachedStorage<S> {
+    async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        let inner = self.fallback.clone();
+        self.plugins_cache.get(|| async move { (*inner).as_ref().plugins().await }).await
+    }
+
+    async fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, StorageError> {
+        let key = (name.to_owned(), version.to_owned(), hash.to_owned());
+        match self.artifacts_cache.get_entry(&key) {
+            Some(artifact) if artifact.is_current().await => {
+                METRICS.cache_lookup("artifacts", true);
+                Ok(Artifact {
+                    file: artifact.file.into(),
+                    hash: key.2,
+                })
+            },
+            _ => {
+                METRICS.cache_lookup("artifacts", false);
+                let new_artifact = self.inner().get_artifact(name, version, hash).await?;
+                if let Some(file) = CachedFile::of(&new_artifact.file) {
+                    if let Some(artifact) = CachedArtifact::new(file).await {
+                        self.artifacts_cache.insert_entry(key, artifact);
+                    }
+                }
+                Ok(new_artifact)
+            },
         }
     }
 
-    fn get_image(&self, name: &str) -> Result<bytes::Bytes, std::io::Error> {
-        let mut cached = self.images_cache.get(|| HashMap::new());
-        if let Some(bytes) = cached.get(name) {
-            Ok(bytes.to_owned())
+    async fn count_download(&self, name: &str, version: &str, hash: &str) {
+        self.inner().count_download(name, version, hash).await
+    }
+
+    async fn get_image(&self, name: &str) -> Result<StorageFile, StorageError> {
+        if let Some(file) = self.images_cache.get_entry(name) {
+            METRICS.cache_lookup("images", true);
+            Ok(file.into())
         } else {
-            let new_image = self.fallback.as_ref().get_image(name)?;
-            cached.insert(name.to_owned(), new_image.clone());
-            self.images_cache.refresh(cached);
+            METRICS.cache_lookup("images", false);
+            let new_image = self.inner().get_image(name).await?;
+            if let Some(file) = CachedFile::of(&new_image) {
+                self.images_cache.insert_entry(name.to_owned(), file);
+            }
             Ok(new_image)
         }
     }
 
-    fn get_statistics(&self) -> std::collections::HashMap<String, u64> {
-        self.statistics_cache.get(|| self.fallback.as_ref().get_statistics())
+    async fn get_statistics(&self) -> Result<std::collections::HashMap<String, u64>, StorageError> {
+        let inner = self.fallback.clone();
+        self.statistics_cache.get(|| async move { (*inner).as_ref().get_statistics().await }).await
+    }
+
+    async fn publish(&self, name: &str, version: &str, upload: PluginUpload) -> Result<decky_api::StorePluginVersion, StorageError> {
+        let published = self.inner().publish(name, version, upload).await?;
+        self.reload_plugins().await;
+        Ok(published)
+    }
+
+    async fn delete_version(&self, name: &str, version: &str) -> Result<(), StorageError> {
+        self.inner().delete_version(name, version).await?;
+        self.artifacts_cache.value.write()
+            .expect("Failed to acquire cache write lock")
+            .retain(|(cached_name, cached_version, _), _| (cached_name.as_str(), cached_version.as_str()) != (name, version));
+        self.reload_plugins().await;
+        Ok(())
+    }
+
+    async fn update_version(&self, name: &str, version: &str, update: VersionUpdate) -> Result<(), StorageError> {
+        self.inner().update_version(name, version, update).await?;
+        self.reload_plugins().await;
+        Ok(())
+    }
+
+    async fn health(&self) -> StoreHealth {
+        let start = std::time::Instant::now();
+        // cached data is only as good as the store it came from
+        let inner = self.inner().health().await;
+        StoreHealth::new("cache", inner.status, None, start.elapsed())
+            .with_children(vec![inner])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+    use crate::test_util::{plugin, TempDir};
+
+    /// Returns whatever plugins are set, or an error when there are none
+    #[derive(Default)]
+    struct FlakyStorage {
+        plugins: Mutex<Option<StorePluginList>>,
+        calls: AtomicUsize,
+        panics: AtomicBool,
+    }
+
+    #[async_trait]
+    impl IStorage for FlakyStorage {
+        async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            // let concurrent requests run in between
+            actix_web::rt::task::yield_now().await;
+            if self.panics.load(Ordering::SeqCst) {
+                panic!("Store crashed");
+            }
+            self.plugins.lock().unwrap().clone()
+                .ok_or_else(|| StorageError::Unavailable("Connection refused".to_owned()))
+        }
+    }
+
+    /// Serves a single artifact from disk, if it has the requested hash
+    struct FileArtifactStorage {
+        path: PathBuf,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl IStorage for FileArtifactStorage {
+        async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+            Ok(Vec::new())
+        }
+
+        async fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, StorageError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if (name, version) != ("Example", "1.0.0") || sha256::digest(std::fs::read(&self.path)?.as_slice()) != hash {
+                return Err(StorageError::NotFound("No such artifact".to_owned()));
+            }
+            Ok(Artifact {
+                file: StorageFile::Path(self.path.clone()),
+                hash: hash.to_owned(),
+            })
+        }
+    }
+
+    fn set_plugins(store: &FlakyStorage, names: Option<&[&str]>) {
+        *store.plugins.lock().unwrap() = names.map(|names| names.iter()
+            .map(|name| plugin(name, &[]))
+            .collect());
+    }
+
+    async fn names<S: AsRef<dyn IStorage> + Send + Sync + 'static>(cache: &CachedStorage<S>) -> Vec<String> {
+        cache.plugins().await.unwrap().into_iter().map(|p| p.name).collect()
+    }
+
+    async fn revalidated<S: AsRef<dyn IStorage> + Send + Sync + 'static>(cache: &CachedStorage<S>) {
+        cache.plugins_cache.invalidate();
+        names(cache).await;
+        while cache.plugins_cache.refreshing.load(Ordering::Acquire) {
+            actix_web::rt::task::yield_now().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn stale_while_revalidate() {
+        let store = Arc::new(FlakyStorage::default());
+        let cache = CachedStorage::new(60, store.clone() as Arc<dyn IStorage>);
+        assert!(cache.plugins().await.is_err());
+        set_plugins(&store, Some(&["PowerTools"]));
+        assert_eq!(names(&cache).await, ["PowerTools"]);
+        assert_eq!(store.calls.load(Ordering::SeqCst), 2);
+
+        // expired requests get the stale list while a single refresh runs
+        set_plugins(&store, Some(&["PowerTools", "CSS Loader"]));
+        cache.plugins_cache.invalidate();
+        let (first, second) = futures_util::future::join(names(&cache), names(&cache)).await;
+        assert_eq!((first, second), (vec!["PowerTools".to_owned()], vec!["PowerTools".to_owned()]));
+        while cache.plugins_cache.refreshing.load(Ordering::Acquire) {
+            actix_web::rt::task::yield_now().await;
+        }
+        assert_eq!(store.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(names(&cache).await, ["PowerTools", "CSS Loader"]);
+
+        // failed and empty refreshes keep the last good list
+        set_plugins(&store, None);
+        revalidated(&cache).await;
+        set_plugins(&store, Some(&[]));
+        revalidated(&cache).await;
+        assert_eq!(store.calls.load(Ordering::SeqCst), 5);
+        assert_eq!(names(&cache).await, ["PowerTools", "CSS Loader"]);
+    }
+
+    #[actix_web::test]
+    async fn single_flight() {
+        let store = Arc::new(FlakyStorage::default());
+        set_plugins(&store, Some(&["PowerTools"]));
+        let cache = CachedStorage::new(60, store.clone() as Arc<dyn IStorage>);
+
+        // concurrent first requests share a single fetch
+        let (first, second) = futures_util::future::join(names(&cache), names(&cache)).await;
+        assert_eq!((first, second), (vec!["PowerTools".to_owned()], vec!["PowerTools".to_owned()]));
+        assert_eq!(store.calls.load(Ordering::SeqCst), 1);
+
+        // a refresh which panics doesn't block later ones
+        store.panics.store(true, Ordering::SeqCst);
+        cache.plugins_cache.invalidate();
+        names(&cache).await;
+        for _ in 0..100 {
+            if !cache.plugins_cache.refreshing.load(Ordering::Acquire) {
+                break;
+            }
+            actix_web::rt::task::yield_now().await;
+        }
+        assert!(!cache.plugins_cache.refreshing.load(Ordering::Acquire));
+        store.panics.store(false, Ordering::SeqCst);
+        set_plugins(&store, Some(&["PowerTools", "CSS Loader"]));
+        revalidated(&cache).await;
+        assert_eq!(names(&cache).await, ["PowerTools", "CSS Loader"]);
+    }
+
+    #[actix_web::test]
+    async fn cached_artifacts() {
+        let dir = TempDir::new("cache-artifacts");
+        let path = dir.join("artifact.zip");
+        std::fs::write(&path, b"zip").unwrap();
+        let hash = sha256::digest(b"zip".as_slice());
+        let store = Arc::new(FileArtifactStorage { path: path.clone(), calls: AtomicUsize::new(0) });
+        let cache = CachedStorage::new(60, store.clone() as Arc<dyn IStorage>);
+        let calls = || store.calls.load(Ordering::SeqCst);
+
+        assert_eq!(cache.get_artifact("Example", "1.0.0", &hash).await.unwrap().hash, hash);
+        assert!(cache.get_artifact("Example", "1.0.0", &hash).await.is_ok());
+        assert_eq!(calls(), 1);
+
+        // a cached hash is only served for the version it was checked for
+        assert!(matches!(cache.get_artifact("Other", "1.0.0", &hash).await, Err(StorageError::NotFound(_))));
+        assert_eq!(calls(), 2);
+
+        // a replaced file is checked again
+        std::fs::write(&path, b"other zip").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(60)).unwrap();
+        assert!(matches!(cache.get_artifact("Example", "1.0.0", &hash).await, Err(StorageError::NotFound(_))));
+        assert_eq!(calls(), 3);
     }
 }
@@ -0,0 +1,66 @@
+/// Why a storage operation failed
+#[derive(Debug)]
+pub enum StorageError {
+    /// The plugin, version or file does not exist in this store
+    NotFound(String),
+    /// The request itself is invalid, e.g. a bad name or upload
+    InvalidInput(String),
+    /// The version being published already exists
+    AlreadyExists(String),
+    /// Stored data could not be read back
+    Corrupt(String),
+    /// The store does not support the operation
+    Unsupported(String),
+    /// An upstream store answered, but with an error or unusable data
+    Upstream(String),
+    /// An upstream store could not be reached
+    Unavailable(String),
+    /// Any other I/O failure, e.g. missing permissions
+    Io(std::io::Error),
+}
+
+impl StorageError {
+    /// Short machine-readable name of the variant
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "not_found",
+            Self::InvalidInput(_) => "invalid_input",
+            Self::AlreadyExists(_) => "already_exists",
+            Self::Corrupt(_) => "corrupt_data",
+            Self::Unsupported(_) => "unsupported",
+            Self::Upstream(_) => "upstream_error",
+            Self::Unavailable(_) => "upstream_unavailable",
+            Self::Io(_) => "io",
+        }
+    }
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(msg)
+            | Self::InvalidInput(msg)
+            | Self::AlreadyExists(msg)
+            | Self::Corrupt(msg)
+            | Self::Unsupported(msg)
+            | Self::Upstream(msg)
+            | Self::Unavailable(msg) => write!(f, "{}", msg),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => Self::NotFound(e.to_string()),
+            std::io::ErrorKind::InvalidInput => Self::InvalidInput(e.to_string()),
+            std::io::ErrorKind::AlreadyExists => Self::AlreadyExists(e.to_string()),
+            std::io::ErrorKind::InvalidData => Self::Corrupt(e.to_string()),
+            std::io::ErrorKind::Unsupported => Self::Unsupported(e.to_string()),
+            _ => Self::Io(e),
+        }
+    }
+}
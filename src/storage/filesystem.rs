@@ -1,50 +1,203 @@
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::RwLock;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use std::fs::File;
-use std::io::Read;
 
 use decky_api::{StorePlugin, StorePluginList, StorePluginVersion};
 
+use async_trait::async_trait;
 use serde::{Serialize, Deserialize};
 
-use super::IStorage;
+use super::{Artifact, HealthStatus, IStorage, PluginUpload, StorageError, StorageFile, StoreHealth, VersionUpdate};
+use super::fs_util::{create_file_atomic, safe_file_stem, write_file_atomic};
+use super::fs_watch::{Dirty, PluginWatcher};
+use super::hash_index::HashIndex;
+use super::stats_journal::StatsJournal;
+use super::version_order::sort_newest_first;
+use super::zip_metadata::ZipMetadata;
 
-#[derive(Serialize, Deserialize, Clone)]
+const STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Contents of a plugin's `plugin.json` sidecar, where missing fields are read from the newest zip
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct PluginMetadata {
-    id: usize,
-    author: String,
-    description: String,
-    tags: Vec<String>,
+    #[serde(default)]
+    id: Option<usize>,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
 }
 
 impl PluginMetadata {
+    fn is_complete(&self) -> bool {
+        self.author.is_some() && self.description.is_some() && self.tags.is_some()
+    }
+
+    fn fill_from(&mut self, zip: &ZipMetadata) {
+        if self.author.is_none() {
+            self.author = zip.author.clone();
+        }
+        if self.description.is_none() {
+            self.description = zip.description.clone();
+        }
+        if self.tags.is_none() {
+            self.tags = zip.tags.clone();
+        }
+    }
+
     fn complete(self, name: String, versions: Vec<StorePluginVersion>, image: String) -> StorePlugin {
         StorePlugin {
-            id: self.id,
-            name: name,
-            versions: versions,
-            author: self.author,
-            description: self.description,
-            tags: self.tags,
+            id: self.id.unwrap_or_else(|| id_from_name(&name)),
+            name,
+            versions,
+            author: self.author.unwrap_or_default(),
+            description: self.description.unwrap_or_default(),
+            tags: self.tags.unwrap_or_default(),
             image_url: image,
+            ..Default::default()
         }
     }
 }
 
+/// A store which cannot list its own plugins is broken, rather than missing something the client asked for
+fn listing_error(e: std::io::Error) -> StorageError {
+    log::error!("Plugins read error: {}", e);
+    match e.kind() {
+        std::io::ErrorKind::InvalidData => StorageError::Corrupt(e.to_string()),
+        _ => StorageError::Io(e),
+    }
+}
+
+/// Stable id for plugins without one in `plugin.json` (32-bit FNV-1a of the name)
+pub(super) fn id_from_name(name: &str) -> usize {
+    let hash = name.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+    (hash & 0x7fff_ffff) as usize
+}
+
+/// Administrative flags for a plugin's versions, stored in `state.json` next to `plugin.json`
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct VersionState {
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    hidden: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    yanked: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    yanked_reason: Option<String>,
+}
+
+impl VersionState {
+    fn is_default(&self) -> bool {
+        !self.hidden && !self.yanked && self.yanked_reason.is_none()
+    }
+}
+
+/// A plugin folder as read from disk, including hidden versions
+#[derive(Clone)]
+struct ScannedPlugin {
+    plugin: StorePlugin,
+    hidden: HashSet<String>,
+}
+
+impl ScannedPlugin {
+    /// The plugin as it should be listed, or `None` if all of its versions are hidden
+    fn into_listed(self, include_hidden: bool) -> Option<StorePlugin> {
+        let mut plugin = self.plugin;
+        if include_hidden || self.hidden.is_empty() {
+            return Some(plugin);
+        }
+        plugin.versions.retain(|version| !self.hidden.contains(&version.name));
+        if plugin.versions.is_empty() {
+            None
+        } else {
+            Some(plugin)
+        }
+    }
+}
+
+/// In-memory plugin list which is updated incrementally as files change
+struct WatchedIndex {
+    watcher: PluginWatcher,
+    plugins: Mutex<BTreeMap<String, ScannedPlugin>>,
+    /// Number of times every plugin was re-read, which should only happen on startup and after watch errors
+    rescans: AtomicUsize,
+}
+
+/// Plugins stored in a folder on disk.
+/// Filesystem access blocks, so it runs on the blocking thread pool with a shared handle to the store.
 pub struct FileStorage {
-    stats: Option<RwLock<HashMap<String, AtomicU64>>>, // TODO collect hit counts on actions
+    store: Arc<FileStore>,
+}
+
+struct FileStore {
+    stats: Option<Arc<StatsJournal>>,
+    hashes: HashIndex,
+    zip_metadata: RwLock<HashMap<String, ZipMetadata>>,
+    index: Option<WatchedIndex>,
     root: PathBuf,
     domain_root: String,
+    state_lock: Mutex<()>,
 }
 
 impl FileStorage {
     pub fn new(root: PathBuf, domain_root: String, enable_stats: bool) -> Self {
         Self {
-            root: root,
-            domain_root: domain_root,
-            stats: if enable_stats { Some(RwLock::new(HashMap::new())) } else { None },
+            store: Arc::new(FileStore::new(root, domain_root, enable_stats)),
+        }
+    }
+
+    /// Watch the plugins folder for changes instead of re-reading it on every request
+    pub fn with_watch(mut self) -> Self {
+        Arc::get_mut(&mut self.store)
+            .expect("File storage must be configured before it is shared")
+            .watch();
+        self
+    }
+
+    async fn blocking<T: Send + 'static, F: FnOnce(&FileStore) -> T + Send + 'static>(&self, op: F) -> Result<T, StorageError> {
+        let store = self.store.clone();
+        actix_web::rt::task::spawn_blocking(move || op(&store))
+            .await
+            .map_err(|e| StorageError::Io(std::io::Error::other(e)))
+    }
+}
+
+impl FileStore {
+    fn new(root: PathBuf, domain_root: String, enable_stats: bool) -> Self {
+        let stats = if enable_stats {
+            let journal = Arc::new(StatsJournal::load(root.join("stats.json")));
+            journal.spawn_flusher(STATS_FLUSH_INTERVAL);
+            Some(journal)
+        } else {
+            None
+        };
+        Self {
+            hashes: HashIndex::load(root.join("hashes.json")),
+            index: None,
+            zip_metadata: RwLock::new(HashMap::new()),
+            root,
+            domain_root,
+            stats,
+            state_lock: Mutex::new(()),
+        }
+    }
+
+    fn watch(&mut self) {
+        // only existing folders can be watched
+        if let Err(e) = std::fs::create_dir_all(self.plugins_path()) {
+            log::error!("Failed to create {}: {}", self.plugins_path().display(), e);
+        }
+        match PluginWatcher::new(&self.plugins_path()) {
+            Ok(watcher) => self.index = Some(WatchedIndex {
+                watcher,
+                plugins: Mutex::new(BTreeMap::new()),
+                rescans: AtomicUsize::new(0),
+            }),
+            Err(e) => log::error!("Failed to watch {}, falling back to re-reading it: {}", self.plugins_path().display(), e),
         }
     }
 
@@ -56,6 +209,10 @@ impl FileStorage {
         plugin_root.as_ref().join("plugin.json")
     }
 
+    fn plugin_state_path(&self, plugin_root: impl AsRef<Path>) -> PathBuf {
+        plugin_root.as_ref().join("state.json")
+    }
+
     fn plugin_root_path(&self, plugin_name: &str) -> PathBuf {
         self.plugins_path().join(plugin_name)
     }
@@ -67,12 +224,135 @@ impl FileStorage {
 
     fn plugin_image_path(&self, plugin_name: &str) -> PathBuf {
         self.plugin_root_path(plugin_name)
-            .join(format!("image.png"))
+            .join("image.png")
     }
 
-    fn read_all_plugins(&self) -> std::io::Result<StorePluginList> {
+    fn artifact_url(&self, plugin_name: &str, version_name: &str, hash: &str) -> String {
+        format!("{}/plugins/{}/{}/{}.zip", self.domain_root, plugin_name, version_name, hash)
+    }
+
+    fn read_version_states(&self, plugin_root: impl AsRef<Path>) -> std::io::Result<HashMap<String, VersionState>> {
+        let state_path = self.plugin_state_path(plugin_root);
+        match File::open(&state_path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| {
+                log::error!("`{}` JSON err: {}", state_path.display(), e);
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_version_states(&self, plugin_root: impl AsRef<Path>, states: &HashMap<String, VersionState>) -> std::io::Result<()> {
+        let state_path = self.plugin_state_path(plugin_root);
+        if states.is_empty() {
+            return match std::fs::remove_file(&state_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let json = serde_json::to_vec_pretty(states)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        write_file_atomic(&state_path, &json)
+    }
+
+    fn read_all_plugins(&self, include_hidden: bool) -> std::io::Result<StorePluginList> {
+        let scanned = if let Some(index) = &self.index {
+            self.refresh_index(index)?
+        } else {
+            self.scan_all_plugins()?
+        };
+        if let Err(e) = self.hashes.flush() {
+            log::error!("Failed to save hash index: {}", e);
+        }
+        let mut plugins: StorePluginList = scanned.into_iter()
+            .filter_map(|plugin| plugin.into_listed(include_hidden))
+            .collect();
+        if let Some(stats) = &self.stats {
+            for plugin in &mut plugins {
+                let mut total = 0;
+                for version in &mut plugin.versions {
+                    version.downloads = stats.get(&version.hash);
+                    total += version.downloads.unwrap_or(0);
+                }
+                plugin.downloads = Some(total);
+            }
+        }
+        Ok(plugins)
+    }
+
+    /// Re-read only the plugin folders which changed since the last call
+    fn refresh_index(&self, index: &WatchedIndex) -> std::io::Result<Vec<ScannedPlugin>> {
+        let mut lock = index.plugins.lock().expect("Failed to acquire plugin index lock");
+        match index.watcher.take_dirty() {
+            Dirty::All => match self.scan_all_plugins() {
+                Ok(plugins) => {
+                    index.rescans.fetch_add(1, Ordering::Relaxed);
+                    *lock = plugins.into_iter()
+                        .map(|plugin| (plugin.plugin.name.clone(), plugin))
+                        .collect();
+                },
+                Err(e) => {
+                    index.watcher.mark_all();
+                    return Err(e);
+                }
+            },
+            Dirty::Plugins(names) => for name in names {
+                let path = self.plugin_root_path(&name);
+                if !path.is_dir() {
+                    log::debug!("Plugin {} removed from index", name);
+                    lock.remove(&name);
+                    continue;
+                }
+                match self.read_single_plugin(&path) {
+                    Ok(plugin) => {
+                        log::debug!("Plugin {} updated in index", name);
+                        lock.insert(name, plugin);
+                    },
+                    Err(e) => {
+                        // possibly half-written, try again next time
+                        log::error!("Failed to read plugin {}: {}", name, e);
+                        lock.remove(&name);
+                        index.watcher.mark(name);
+                    }
+                }
+            },
+        }
+        Ok(lock.values().cloned().collect())
+    }
+
+    /// Re-read a plugin on the next listing, instead of waiting for the watcher to report the change
+    fn mark_changed(&self, name: &str) {
+        if let Some(index) = &self.index {
+            index.watcher.mark(name.to_owned());
+        }
+    }
+
+    fn read_zip_metadata(&self, zip_path: &Path, hash: &str) -> std::io::Result<ZipMetadata> {
+        if let Some(cached) = self.zip_metadata.read().expect("Failed to acquire zip metadata read lock").get(hash) {
+            return Ok(cached.clone());
+        }
+        log::debug!("Reading metadata from {}", zip_path.display());
+        let zip_info = match ZipMetadata::read(File::open(zip_path)?) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("No metadata in `{}`: {}", zip_path.display(), e);
+                ZipMetadata::default()
+            }
+        };
+        self.zip_metadata.write()
+            .expect("Failed to acquire zip metadata write lock")
+            .insert(hash.to_owned(), zip_info.clone());
+        Ok(zip_info)
+    }
+
+    fn scan_all_plugins(&self) -> std::io::Result<Vec<ScannedPlugin>> {
         let plugins = self.plugins_path();
-        let dir_reader = plugins.read_dir()?;
+        let dir_reader = match plugins.read_dir() {
+            // a new store, which has nothing published yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            result => result?,
+        };
         let mut results = Vec::with_capacity(dir_reader.size_hint().1.unwrap_or(32));
         for entry in dir_reader {
             let entry = entry?;
@@ -80,119 +360,474 @@ impl FileStorage {
                 results.push(self.read_single_plugin(&entry.path())?);
             }
         }
-        // build stats counters
-        if let Some(stats) = &self.stats {
-            let mut lock = stats.write().expect("Couldn't acquire stats write lock");
-            for plugin in &results {
-                for version in &plugin.versions {
-                    if !lock.contains_key(&version.hash) {
-                        lock.insert(version.hash.clone(), AtomicU64::new(0));
-                    }
-                }
-            }
-        }
         Ok(results)
     }
 
-    fn read_single_plugin(&self, path: &PathBuf) -> std::io::Result<StorePlugin> {
+    fn read_single_plugin(&self, path: &PathBuf) -> std::io::Result<ScannedPlugin> {
         let plugin_name = path.file_name().unwrap().to_string_lossy().into_owned();
         let json_path = self.plugin_json_path(path);
-        let plugin_info: PluginMetadata = match serde_json::from_reader(File::open(&json_path)?) {
-            Ok(x) => x,
-            Err(e) => {
-                log::error!("`{}` JSON err: {}", json_path.display(), e);
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
-            }
+        let mut plugin_info: PluginMetadata = match File::open(&json_path) {
+            Ok(file) => match serde_json::from_reader(file) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::error!("`{}` JSON err: {}", json_path.display(), e);
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => PluginMetadata::default(),
+            Err(e) => return Err(e),
         };
+        let states = self.read_version_states(path)?;
         // find plugin versions
         let dir_reader = path.read_dir()?;
         let mut versions = Vec::with_capacity(dir_reader.size_hint().1.unwrap_or(4));
+        let mut hidden = HashSet::new();
         for entry in dir_reader {
             let entry = entry?;
             if entry.file_type()?.is_file() {
                 let entry_path = entry.path();
-                let extension = entry_path.extension().unwrap().to_string_lossy().into_owned();
-                if extension == "zip" {
+                if entry_path.extension().map(|ext| ext == "zip").unwrap_or(false) {
                     let version_name = entry_path.file_stem().unwrap().to_string_lossy().into_owned();
-                    let hash_str = sha256::try_digest(entry_path.as_ref())?;
-                    let artifact_url = format!("{}/plugins/{}/{}/{}.zip", self.domain_root, plugin_name, version_name, hash_str);
+                    let hash_str = self.hashes.hash(&entry_path)?;
+                    // hidden versions are still downloadable, so they are always counted
+                    if let Some(stats) = &self.stats {
+                        stats.register(&hash_str);
+                    }
+                    let state = states.get(&version_name).cloned().unwrap_or_default();
+                    if state.hidden {
+                        hidden.insert(version_name.clone());
+                    }
+                    let artifact_url = self.artifact_url(&plugin_name, &version_name, &hash_str);
+                    let created = entry.metadata()?.modified().ok()
+                        .map(|mtime| chrono::DateTime::<chrono::Utc>::from(mtime).to_rfc3339());
                     versions.push(StorePluginVersion {
                         name: version_name,
                         hash: hash_str,
-                        artifact: Some(artifact_url)
+                        artifact: Some(artifact_url),
+                        yanked: state.yanked,
+                        yanked_reason: state.yanked_reason,
+                        created,
+                        ..Default::default()
                     });
                 }
             }
         }
-        versions.sort_by(|a, b| b.name.cmp(&a.name)); // sort e.g. v2 before v1
-        let image_url = format!("{}/plugins/{}.png", self.domain_root, plugin_name);
-        Ok(
-            plugin_info.complete(
-                plugin_name,
-                versions,
-                image_url,
-            )
-        )
+        sort_newest_first(&mut versions);
+        let mut zip_image = None;
+        if !plugin_info.is_complete() {
+            if let Some(newest) = versions.first() {
+                let zip_info = self.read_zip_metadata(&path.join(format!("{}.zip", newest.name)), &newest.hash)?;
+                plugin_info.fill_from(&zip_info);
+                zip_image = zip_info.image;
+            }
+        }
+        let image_url = match zip_image {
+            Some(url) if !self.plugin_image_path(&plugin_name).is_file() => url,
+            _ => format!("{}/plugins/{}.png", self.domain_root, plugin_name),
+        };
+        for version in &mut versions {
+            version.image_url = Some(image_url.clone());
+        }
+        let mut plugin = plugin_info.complete(
+            plugin_name,
+            versions,
+            image_url,
+        );
+        // RFC 3339 timestamps in UTC compare correctly as strings
+        plugin.created = plugin.versions.iter().filter_map(|v| v.created.clone()).min();
+        plugin.updated = plugin.versions.iter().filter_map(|v| v.created.clone()).max();
+        Ok(ScannedPlugin {
+            plugin,
+            hidden,
+        })
     }
-}
 
-impl IStorage for FileStorage {
-    fn plugins(&self) -> StorePluginList {
-        match self.read_all_plugins() {
-            Err(e) => {
-                log::error!("Plugins read error: {}", e);
-                vec![]
-            },
-            Ok(x) => x
-        }
+    fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        self.read_all_plugins(false).map_err(listing_error)
     }
 
-    fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<bytes::Bytes, std::io::Error> {
+    fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, std::io::Error> {
         let path = self.plugin_artifact_path(name, version, hash);
-        log::debug!("Opening artifact path: {}", path.display());
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        if let Some(stats) = &self.stats {
-            let lock = stats.read().expect("Failed to acquire stats read lock");
-            if let Some(counter) = lock.get(hash) {
-                counter.fetch_add(1, Ordering::SeqCst);
-            }
+        log::debug!("Serving artifact path: {}", path.display());
+        if !path.is_file() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Artifact {} {} does not exist", name, version)));
+        }
+        let actual_hash = self.hashes.hash(&path)?;
+        if actual_hash != hash {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Artifact {} {} does not have hash {}", name, version, hash)));
         }
-        Ok(buffer.into())
+        Ok(Artifact {
+            file: StorageFile::Path(path),
+            hash: actual_hash,
+        })
     }
 
-    fn get_image(&self, name: &str) -> Result<bytes::Bytes, std::io::Error> {
+    fn get_image(&self, name: &str) -> Result<StorageFile, std::io::Error> {
         let path = self.plugin_image_path(name);
-        log::debug!("Opening image path: {}", path.display());
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        Ok(buffer.into())
+        log::debug!("Serving image path: {}", path.display());
+        if !path.is_file() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Image for {} does not exist", name)));
+        }
+        Ok(StorageFile::Path(path))
     }
 
-    fn get_statistics(&self) -> std::collections::HashMap<String, u64> {
+    fn get_statistics(&self) -> Result<std::collections::HashMap<String, u64>, StorageError> {
         if let Some(stats) = &self.stats {
-            if let Ok(plugins) = self.read_all_plugins() {
-                let lock = stats.read().expect("Failed to acquire stats read lock");
-                let mut map = std::collections::HashMap::with_capacity(lock.len());
-                for plugin in plugins {
-                    let mut total = 0;
-                    for version in plugin.versions {
-                        if let Some(count) = lock.get(&version.hash) {
-                            let count_val = count.load(Ordering::SeqCst);
-                            total += count_val;
-                            map.insert(format!("{} {}", plugin.name, version.name), count_val);
-                        }
+            let plugins = self.read_all_plugins(true).map_err(listing_error)?;
+            let mut map = std::collections::HashMap::with_capacity(stats.len());
+            for plugin in plugins {
+                let mut total = 0;
+                for version in plugin.versions {
+                    if let Some(count_val) = stats.get(&version.hash) {
+                        total += count_val;
+                        map.insert(format!("{} {}", plugin.name, version.name), count_val);
                     }
-                    map.insert(format!("{}", plugin.name), total);
                 }
-                map
-            } else {
-                std::collections::HashMap::with_capacity(0)
+                map.insert(plugin.name, total);
             }
+            Ok(map)
         } else {
-            std::collections::HashMap::with_capacity(0)
+            Ok(std::collections::HashMap::with_capacity(0))
+        }
+    }
+
+    fn health(&self) -> StoreHealth {
+        let start = std::time::Instant::now();
+        let (status, last_error) = match self.read_all_plugins(true) {
+            Ok(_) => (HealthStatus::Healthy, None),
+            Err(e) => (HealthStatus::Failing, Some(e.to_string())),
+        };
+        StoreHealth::new(format!("filesystem {}", self.root.display()), status, last_error, start.elapsed())
+    }
+
+    fn publish(&self, name: &str, version: &str, upload: PluginUpload) -> Result<StorePluginVersion, std::io::Error> {
+        safe_file_stem(name)?;
+        safe_file_stem(version)?;
+        if let Err(e) = zip::ZipArchive::new(std::io::Cursor::new(&upload.artifact)) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Artifact is not a zip file: {}", e)));
+        }
+        let plugin_root = self.plugin_root_path(name);
+        let artifact_path = self.plugin_artifact_path(name, version, "");
+        let json_path = self.plugin_json_path(&plugin_root);
+        // validate everything before writing anything
+        if let Some(metadata) = &upload.metadata {
+            if let Err(e) = serde_json::from_slice::<PluginMetadata>(metadata) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid plugin.json: {}", e)));
+            }
+        }
+        // concurrent uploads of the same version must not both get past the existence check
+        let _lock = self.state_lock.lock().expect("Failed to acquire state lock");
+        if artifact_path.exists() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} v{} already exists", name, version)));
+        }
+        if let Some(metadata) = &upload.metadata {
+            write_file_atomic(&json_path, metadata)?;
+        } else if !json_path.exists() {
+            if let Err(e) = ZipMetadata::read(std::io::Cursor::new(&upload.artifact)) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("plugin.json metadata is required when the zip has none ({})", e)));
+            }
+        }
+        if let Some(image) = &upload.image {
+            write_file_atomic(&self.plugin_image_path(name), image)?;
+        }
+        // the artifact goes last, since its existence is what makes the version visible
+        create_file_atomic(&artifact_path, &upload.artifact)?;
+        let hash = sha256::digest(upload.artifact.as_ref());
+        self.hashes.insert(&artifact_path, hash.clone())?;
+        self.mark_changed(name);
+        if let Some(stats) = &self.stats {
+            stats.register(&hash);
+        }
+        log::info!("Published {} v{} ({})", name, version, hash);
+        Ok(StorePluginVersion {
+            name: version.to_owned(),
+            artifact: Some(self.artifact_url(name, version, &hash)),
+            hash,
+            created: Some(chrono::Utc::now().to_rfc3339()),
+            ..Default::default()
+        })
+    }
+
+    fn delete_version(&self, name: &str, version: &str) -> Result<(), std::io::Error> {
+        safe_file_stem(name)?;
+        safe_file_stem(version)?;
+        let _lock = self.state_lock.lock().expect("Failed to acquire state lock");
+        std::fs::remove_file(self.plugin_artifact_path(name, version, ""))?;
+        self.mark_changed(name);
+        let plugin_root = self.plugin_root_path(name);
+        let mut states = self.read_version_states(&plugin_root)?;
+        if states.remove(version).is_some() {
+            self.write_version_states(&plugin_root, &states)?;
+        }
+        log::info!("Deleted {} v{}", name, version);
+        Ok(())
+    }
+
+    fn update_version(&self, name: &str, version: &str, update: VersionUpdate) -> Result<(), std::io::Error> {
+        safe_file_stem(name)?;
+        safe_file_stem(version)?;
+        let _lock = self.state_lock.lock().expect("Failed to acquire state lock");
+        if !self.plugin_artifact_path(name, version, "").is_file() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} v{} does not exist", name, version)));
+        }
+        let plugin_root = self.plugin_root_path(name);
+        let mut states = self.read_version_states(&plugin_root)?;
+        let state = states.entry(version.to_owned()).or_default();
+        match update.clone() {
+            VersionUpdate::Hide => state.hidden = true,
+            VersionUpdate::Unhide => state.hidden = false,
+            VersionUpdate::Yank(reason) => {
+                state.yanked = true;
+                state.yanked_reason = reason;
+            },
+            VersionUpdate::Unyank => {
+                state.yanked = false;
+                state.yanked_reason = None;
+            },
+        }
+        if state.is_default() {
+            states.remove(version);
+        }
+        self.write_version_states(&plugin_root, &states)?;
+        self.mark_changed(name);
+        log::info!("Applied {:?} to {} v{}", update, name, version);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IStorage for FileStorage {
+    async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        self.blocking(|store| store.plugins()).await?
+    }
+
+    async fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, StorageError> {
+        let (name, version, hash) = (name.to_owned(), version.to_owned(), hash.to_owned());
+        Ok(self.blocking(move |store| store.get_artifact(&name, &version, &hash)).await??)
+    }
+
+    async fn count_download(&self, _name: &str, _version: &str, hash: &str) {
+        if let Some(stats) = &self.store.stats {
+            stats.increment(hash);
+        }
+    }
+
+    async fn get_image(&self, name: &str) -> Result<StorageFile, StorageError> {
+        let name = name.to_owned();
+        Ok(self.blocking(move |store| store.get_image(&name)).await??)
+    }
+
+    async fn get_statistics(&self) -> Result<std::collections::HashMap<String, u64>, StorageError> {
+        self.blocking(|store| store.get_statistics()).await?
+    }
+
+    async fn health(&self) -> StoreHealth {
+        let start = std::time::Instant::now();
+        match self.blocking(|store| store.health()).await {
+            Ok(health) => health,
+            Err(e) => StoreHealth::new(format!("filesystem {}", self.store.root.display()), HealthStatus::Failing, Some(e.to_string()), start.elapsed()),
         }
     }
+
+    async fn publish(&self, name: &str, version: &str, upload: PluginUpload) -> Result<StorePluginVersion, StorageError> {
+        let (name, version) = (name.to_owned(), version.to_owned());
+        Ok(self.blocking(move |store| store.publish(&name, &version, upload)).await??)
+    }
+
+    async fn delete_version(&self, name: &str, version: &str) -> Result<(), StorageError> {
+        let (name, version) = (name.to_owned(), version.to_owned());
+        Ok(self.blocking(move |store| store.delete_version(&name, &version)).await??)
+    }
+
+    async fn update_version(&self, name: &str, version: &str, update: VersionUpdate) -> Result<(), StorageError> {
+        let (name, version) = (name.to_owned(), version.to_owned());
+        Ok(self.blocking(move |store| store.update_version(&name, &version, update)).await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    const DOMAIN: &str = "http://localhost:22252";
+
+    /// Write a version of the `Example` plugin, returning its hash
+    fn add_version(dir: &TempDir, version: &str) -> String {
+        let plugin_root = dir.join("store").join("plugins").join("Example");
+        std::fs::create_dir_all(&plugin_root).unwrap();
+        let artifact = test_util::zip(&[
+            ("Example/plugin.json", r#"{"name": "Example", "author": "Jane"}"#),
+            ("Example/VERSION", version),
+        ]);
+        std::fs::write(plugin_root.join(format!("{}.zip", version)), &artifact).unwrap();
+        sha256::digest(artifact.as_slice())
+    }
+
+    /// A store with a single plugin version, returning the artifact hash
+    fn store_with_version(dir: &TempDir) -> (FileStore, String) {
+        let hash = add_version(dir, "1.0.0");
+        (FileStore::new(dir.join("store"), DOMAIN.to_owned(), false), hash)
+    }
+
+    #[test]
+    fn empty_store() {
+        let dir = TempDir::new("filesystem-empty");
+        let store = FileStore::new(dir.join("store"), DOMAIN.to_owned(), false);
+        assert!(store.plugins().unwrap().is_empty());
+        assert!(store.get_statistics().unwrap().is_empty());
+        assert_eq!(store.health().status, HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn artifact_hash() {
+        let dir = TempDir::new("filesystem-hash");
+        let (store, hash) = store_with_version(&dir);
+        let artifact = store.get_artifact("Example", "1.0.0", &hash).unwrap();
+        assert_eq!(artifact.hash, hash);
+        assert!(matches!(artifact.file, StorageFile::Path(_)));
+        let err = store.get_artifact("Example", "1.0.0", "deadbeef").err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn publish() {
+        let dir = TempDir::new("filesystem-publish");
+        let store = FileStore::new(dir.join("store"), DOMAIN.to_owned(), false);
+        let artifact = bytes::Bytes::from(test_util::zip(&[("Example/plugin.json", r#"{"name": "Example"}"#)]));
+        let upload = |metadata: &str| PluginUpload {
+            artifact: artifact.clone(),
+            metadata: Some(bytes::Bytes::from(metadata.to_owned())),
+            image: None,
+        };
+        let published = store.publish("Example", "1.0.0", upload(r#"{"author": "Jane"}"#)).unwrap();
+        assert_eq!(published.hash, sha256::digest(artifact.as_ref()));
+        assert_eq!(store.plugins().unwrap()[0].author, "Jane");
+
+        // a duplicate is rejected before anything is replaced
+        let err = store.publish("Example", "1.0.0", upload(r#"{"author": "Mallory"}"#)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        let err = store.publish("Example", "1.1.0", PluginUpload {
+            artifact: bytes::Bytes::from_static(b"not a zip"),
+            ..upload(r#"{"author": "Mallory"}"#)
+        }).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(store.plugins().unwrap()[0].author, "Jane");
+
+        // no temporary files are left behind
+        let mut files: Vec<_> = std::fs::read_dir(store.plugin_root_path("Example")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["1.0.0.zip", "plugin.json"]);
+    }
+
+    #[test]
+    fn version_states() {
+        let dir = TempDir::new("filesystem-states");
+        let (store, old_hash) = store_with_version(&dir);
+        let new_hash = add_version(&dir, "1.1.0");
+        store.update_version("Example", "1.0.0", VersionUpdate::Yank(Some("Broken".to_owned()))).unwrap();
+        store.update_version("Example", "1.1.0", VersionUpdate::Hide).unwrap();
+
+        // the flags are kept in state.json, so they survive a restart
+        let store = FileStore::new(dir.join("store"), DOMAIN.to_owned(), false);
+        let versions = &store.plugins().unwrap()[0].versions;
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].name, "1.0.0");
+        assert!(versions[0].yanked);
+        assert_eq!(versions[0].yanked_reason.as_deref(), Some("Broken"));
+        // hidden versions can still be downloaded
+        assert!(store.get_artifact("Example", "1.1.0", &new_hash).is_ok());
+
+        store.update_version("Example", "1.0.0", VersionUpdate::Unyank).unwrap();
+        store.update_version("Example", "1.1.0", VersionUpdate::Unhide).unwrap();
+        let versions = &store.plugins().unwrap()[0].versions;
+        assert_eq!(versions.len(), 2);
+        assert!(versions.iter().all(|version| !version.yanked && version.yanked_reason.is_none()));
+        assert!(!store.plugin_state_path(store.plugin_root_path("Example")).exists());
+
+        store.update_version("Example", "1.1.0", VersionUpdate::Hide).unwrap();
+        store.delete_version("Example", "1.1.0").unwrap();
+        assert!(!store.plugin_state_path(store.plugin_root_path("Example")).exists());
+        assert_eq!(store.plugins().unwrap()[0].versions.len(), 1);
+        assert_eq!(store.get_artifact("Example", "1.1.0", &new_hash).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(store.delete_version("Example", "1.1.0").err().unwrap().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(store.update_version("Example", "1.1.0", VersionUpdate::Hide).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+        assert!(store.get_artifact("Example", "1.0.0", &old_hash).is_ok());
+    }
+
+    #[test]
+    fn watched_changes() {
+        let dir = TempDir::new("filesystem-watch");
+        let plugin_root = dir.join("store").join("plugins").join("Example");
+        let mut store = FileStore::new(dir.join("store"), DOMAIN.to_owned(), false);
+        store.watch();
+        assert!(store.plugins().unwrap().is_empty());
+        let wait_for = |description: &str, check: &dyn Fn(&StorePluginList) -> bool| {
+            let start = std::time::Instant::now();
+            while !check(&store.plugins().unwrap()) {
+                assert!(start.elapsed() < Duration::from_secs(10), "Index never showed {}", description);
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        };
+        let zip_image = "https://example.com/example.png";
+        let write_zip = |author: &str| {
+            let plugin_json = format!(r#"{{"name": "Example", "author": "{}", "publish": {{"image": "{}"}}}}"#, author, zip_image);
+            let artifact = test_util::zip(&[("Example/plugin.json", &plugin_json)]);
+            std::fs::write(plugin_root.join("1.0.0.zip"), &artifact).unwrap();
+            sha256::digest(artifact.as_slice())
+        };
+
+        std::fs::create_dir_all(&plugin_root).unwrap();
+        let hash = write_zip("Jane");
+        wait_for("the new zip", &|plugins| plugins.len() == 1 && plugins[0].versions[0].hash == hash);
+        assert_eq!(store.plugins().unwrap()[0].image_url, zip_image);
+        let hash = write_zip("John");
+        wait_for("the changed zip", &|plugins| plugins[0].versions[0].hash == hash && plugins[0].author == "John");
+
+        std::fs::write(plugin_root.join("plugin.json"), r#"{"author": "Jim"}"#).unwrap();
+        wait_for("the new plugin.json", &|plugins| plugins[0].author == "Jim");
+        std::fs::write(plugin_root.join("plugin.json"), r#"{"author": "Joe"}"#).unwrap();
+        wait_for("the changed plugin.json", &|plugins| plugins[0].author == "Joe");
+        std::fs::remove_file(plugin_root.join("plugin.json")).unwrap();
+        wait_for("the removed plugin.json", &|plugins| plugins[0].author == "John");
+
+        let local_image = format!("{}/plugins/Example.png", DOMAIN);
+        std::fs::write(plugin_root.join("image.png"), b"png").unwrap();
+        wait_for("the new image", &|plugins| plugins[0].image_url == local_image);
+        std::fs::remove_file(plugin_root.join("image.png")).unwrap();
+        wait_for("the removed image", &|plugins| plugins[0].image_url == zip_image);
+
+        std::fs::remove_file(plugin_root.join("1.0.0.zip")).unwrap();
+        wait_for("the removed zip", &|plugins| plugins[0].versions.is_empty());
+        std::fs::remove_dir(&plugin_root).unwrap();
+        wait_for("the removed folder", &|plugins| plugins.is_empty());
+        // only the first listing read everything
+        assert_eq!(store.index.as_ref().unwrap().rescans.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn watched_writes() {
+        let dir = TempDir::new("filesystem-watch-writes");
+        let mut store = FileStore::new(dir.join("store"), DOMAIN.to_owned(), false);
+        store.watch();
+        // a watcher on another folder never reports the changes below, so the writes have to mark them
+        std::fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        store.index.as_mut().unwrap().watcher = PluginWatcher::new(&dir.join("elsewhere")).unwrap();
+        assert!(store.plugins().unwrap().is_empty());
+
+        // changes made through the store are listed right away, without waiting for the watcher
+        let artifact = bytes::Bytes::from(test_util::zip(&[("Example/plugin.json", r#"{"name": "Example"}"#)]));
+        store.publish("Example", "1.0.0", PluginUpload {
+            artifact,
+            metadata: Some(bytes::Bytes::from_static(br#"{"author": "Jane"}"#)),
+            image: None,
+        }).unwrap();
+        assert_eq!(store.plugins().unwrap()[0].versions.len(), 1);
+        store.update_version("Example", "1.0.0", VersionUpdate::Yank(None)).unwrap();
+        assert!(store.plugins().unwrap()[0].versions[0].yanked);
+        store.delete_version("Example", "1.0.0").unwrap();
+        assert!(store.plugins().unwrap()[0].versions.is_empty());
+    }
 }
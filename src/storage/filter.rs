@@ -0,0 +1,337 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use decky_api::{StorePlugin, StorePluginList};
+use serde::Deserialize;
+
+use super::{Artifact, IStorage, PluginUpload, StorageError, StorageFile, StoreHealth, VersionUpdate};
+
+/// Case-insensitive glob patterns, where `*` matches any text and `?` any single character.
+/// Version patterns are `<plugin>@<version>`, or just `<version>` to match a version of any plugin.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterRules {
+    pub names: Vec<String>,
+    pub authors: Vec<String>,
+    pub tags: Vec<String>,
+    pub versions: Vec<String>,
+}
+
+impl FilterRules {
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.authors.is_empty() && self.tags.is_empty() && self.versions.is_empty()
+    }
+
+    fn has_plugin_rules(&self) -> bool {
+        !(self.names.is_empty() && self.authors.is_empty() && self.tags.is_empty())
+    }
+
+    /// Whether checking a plugin needs more than its name
+    fn has_listing_rules(&self) -> bool {
+        !(self.authors.is_empty() && self.tags.is_empty())
+    }
+
+    fn matches_plugin(&self, plugin: &StorePlugin) -> bool {
+        self.names.iter().any(|pattern| glob_match(pattern, &plugin.name))
+            || self.authors.iter().any(|pattern| glob_match(pattern, &plugin.author))
+            || self.tags.iter().any(|pattern| plugin.tags.iter().any(|tag| glob_match(pattern, tag)))
+    }
+
+    fn matches_version(&self, plugin_name: &str, version_name: &str) -> bool {
+        self.versions.iter().any(|pattern| match pattern.split_once('@') {
+            Some((plugin_pattern, version_pattern)) => glob_match(plugin_pattern, plugin_name) && glob_match(version_pattern, version_name),
+            None => glob_match(pattern, version_name),
+        })
+    }
+}
+
+/// How soon after listing the inner store a download of an unknown artifact may list it again
+const RELIST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Match `text` against a glob `pattern`, ignoring case
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text position it currently matches up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // let the last `*` match one more character
+            backtrack = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Only expose the plugins and versions of the inner store which pass the allow and deny rules.
+/// Artifacts are only served for versions in the filtered listing, so hidden versions aren't downloadable.
+pub struct FilteredStorage<S: AsRef<dyn IStorage> + Send + Sync> {
+    inner: S,
+    /// Plugins and versions must match one of these rules, if there are any
+    allow: FilterRules,
+    /// Plugins and versions matching any of these are removed
+    deny: FilterRules,
+    /// Whether each plugin seen in a listing passed the plugin rules, since author and tags are only known from there
+    verdicts: RwLock<HashMap<String, bool>>,
+    /// `(name, version, hash)` of every artifact in the last filtered listing
+    artifacts: RwLock<HashSet<(String, String, String)>>,
+    /// When the inner store was last listed successfully
+    listed_at: Mutex<Option<Instant>>,
+}
+
+impl<S: AsRef<dyn IStorage> + Send + Sync> FilteredStorage<S> {
+    pub fn new(inner: S, allow: FilterRules, deny: FilterRules) -> Self {
+        Self {
+            inner,
+            allow,
+            deny,
+            verdicts: RwLock::new(HashMap::new()),
+            artifacts: RwLock::new(HashSet::new()),
+            listed_at: Mutex::new(None),
+        }
+    }
+
+    fn plugin_allowed(&self, plugin: &StorePlugin) -> bool {
+        (!self.allow.has_plugin_rules() || self.allow.matches_plugin(plugin))
+            && !self.deny.matches_plugin(plugin)
+    }
+
+    fn version_allowed(&self, plugin_name: &str, version_name: &str) -> bool {
+        (self.allow.versions.is_empty() || self.allow.matches_version(plugin_name, version_name))
+            && !self.deny.matches_version(plugin_name, version_name)
+    }
+
+    fn filter(&self, plugins: StorePluginList) -> StorePluginList {
+        plugins.into_iter()
+            .filter(|plugin| self.plugin_allowed(plugin))
+            .filter_map(|mut plugin| {
+                let name = plugin.name.clone();
+                plugin.versions.retain(|version| self.version_allowed(&name, &version.name));
+                if plugin.versions.is_empty() {
+                    None
+                } else {
+                    Some(plugin)
+                }
+            })
+            .collect()
+    }
+
+    /// Whether a plugin passes the plugin rules, or `None` if that depends on a listing which didn't include it
+    fn name_allowed(&self, name: &str) -> Option<bool> {
+        if !self.allow.has_listing_rules() && !self.deny.has_listing_rules() {
+            return Some(self.plugin_allowed(&StorePlugin {
+                name: name.to_owned(),
+                ..Default::default()
+            }));
+        }
+        self.verdicts.read().expect("Failed to acquire filter verdicts read lock").get(name).copied()
+    }
+
+    fn artifact_listed(&self, name: &str, version: &str, hash: &str) -> bool {
+        self.artifacts.read()
+            .expect("Failed to acquire filtered artifacts read lock")
+            .contains(&(name.to_owned(), version.to_owned(), hash.to_owned()))
+    }
+
+    fn listed_at(&self) -> Option<Instant> {
+        *self.listed_at.lock().expect("Failed to acquire filter listing time lock")
+    }
+
+    /// List the inner store again to pick up new plugins, unless that was just done
+    async fn relist(&self) -> Result<(), StorageError> {
+        if self.listed_at().map(|at| at.elapsed() > RELIST_INTERVAL).unwrap_or(true) {
+            self.plugins().await?;
+        }
+        Ok(())
+    }
+
+    /// Check a plugin for images, without listing the inner store again for every unknown name
+    async fn is_allowed(&self, name: &str) -> Result<bool, StorageError> {
+        if self.name_allowed(name).is_none() {
+            self.relist().await?;
+        }
+        // unknown plugins are only allowed once a listing shows they pass
+        Ok(self.name_allowed(name).unwrap_or(false))
+    }
+
+    fn filtered_out() -> StorageError {
+        StorageError::NotFound("Plugin version is not available in this store".to_owned())
+    }
+}
+
+#[async_trait]
+impl<S: AsRef<dyn IStorage> + Send + Sync> IStorage for FilteredStorage<S> {
+    async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        let plugins = self.inner.as_ref().plugins().await?;
+        let mut verdicts = self.verdicts.write().expect("Failed to acquire filter verdicts write lock");
+        for plugin in &plugins {
+            // hidden versions stay downloadable, so verdicts are kept when a plugin drops out of the list
+            verdicts.insert(plugin.name.clone(), self.plugin_allowed(plugin));
+        }
+        drop(verdicts);
+        let plugins = self.filter(plugins);
+        *self.artifacts.write().expect("Failed to acquire filtered artifacts write lock") = plugins.iter()
+            .flat_map(|plugin| plugin.versions.iter()
+                .map(|version| (plugin.name.clone(), version.name.clone(), version.hash.clone())))
+            .collect();
+        *self.listed_at.lock().expect("Failed to acquire filter listing time lock") = Some(Instant::now());
+        Ok(plugins)
+    }
+
+    async fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, StorageError> {
+        if !self.version_allowed(name, version) {
+            return Err(Self::filtered_out());
+        }
+        if !self.artifact_listed(name, version, hash) {
+            self.relist().await?;
+            if !self.artifact_listed(name, version, hash) {
+                return Err(Self::filtered_out());
+            }
+        }
+        self.inner.as_ref().get_artifact(name, version, hash).await
+    }
+
+    async fn count_download(&self, name: &str, version: &str, hash: &str) {
+        self.inner.as_ref().count_download(name, version, hash).await
+    }
+
+    async fn get_image(&self, name: &str) -> Result<StorageFile, StorageError> {
+        if !self.is_allowed(name).await? {
+            return Err(Self::filtered_out());
+        }
+        self.inner.as_ref().get_image(name).await
+    }
+
+    async fn get_statistics(&self) -> Result<HashMap<String, u64>, StorageError> {
+        if self.listed_at().is_none() {
+            self.plugins().await?;
+        }
+        let statistics = self.inner.as_ref().get_statistics().await?;
+        let verdicts = self.verdicts.read().expect("Failed to acquire filter verdicts read lock");
+        Ok(statistics
+            .into_iter()
+            // keys are either "<plugin>" or "<plugin> <version>"
+            .filter(|(key, _)| match verdicts.get(key) {
+                Some(allowed) => *allowed,
+                None => key.rsplit_once(' ')
+                    .map(|(name, version)| verdicts.get(name).copied().unwrap_or(false) && self.version_allowed(name, version))
+                    .unwrap_or(false),
+            })
+            .collect())
+    }
+
+    async fn publish(&self, name: &str, version: &str, upload: PluginUpload) -> Result<decky_api::StorePluginVersion, StorageError> {
+        self.inner.as_ref().publish(name, version, upload).await
+    }
+
+    async fn delete_version(&self, name: &str, version: &str) -> Result<(), StorageError> {
+        self.inner.as_ref().delete_version(name, version).await
+    }
+
+    async fn update_version(&self, name: &str, version: &str, update: VersionUpdate) -> Result<(), StorageError> {
+        self.inner.as_ref().update_version(name, version, update).await
+    }
+
+    async fn health(&self) -> StoreHealth {
+        let start = std::time::Instant::now();
+        let inner = self.inner.as_ref().health().await;
+        StoreHealth::new("filter", inner.status, None, start.elapsed())
+            .with_children(vec![inner])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, StaticStorage};
+
+    fn plugin(name: &str, author: &str, tags: &[&str], versions: &[(&str, &str)]) -> StorePlugin {
+        StorePlugin {
+            author: author.to_owned(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..test_util::plugin(name, versions)
+        }
+    }
+
+    #[actix_web::test]
+    async fn allow_and_deny() {
+        assert!(glob_match("power*", "PowerTools"));
+        assert!(glob_match("1.?.*", "1.2.3"));
+        assert!(!glob_match("1.?", "1.10"));
+
+        let inner = StaticStorage::new(vec![
+            plugin("PowerTools", "NGnius", &["root"], &[("1.2.0", "a"), ("1.1.0", "b")]),
+            plugin("TabMaster", "Tormak", &["qam"], &[("2.0.0", "c")]),
+            plugin("Untrusted", "Someone", &["root"], &[("0.1.0", "d")]),
+            plugin("Unfinished", "Someone", &["testing"], &[("0.0.1", "e")]),
+        ]);
+        let allow = FilterRules {
+            authors: vec!["ngnius".to_owned(), "tormak".to_owned()],
+            tags: vec!["testing".to_owned()],
+            ..Default::default()
+        };
+        let deny = FilterRules {
+            versions: vec!["PowerTools@1.2.*".to_owned(), "0.0.1".to_owned()],
+            ..Default::default()
+        };
+        let filtered = FilteredStorage::new(inner, allow, deny);
+        let plugins = filtered.plugins().await.unwrap();
+        let names: Vec<&str> = plugins.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["PowerTools", "TabMaster"]);
+        assert_eq!(plugins[0].versions.len(), 1);
+        assert_eq!(plugins[0].versions[0].name, "1.1.0");
+
+        assert!(filtered.get_artifact("PowerTools", "1.1.0", "b").await.is_ok());
+        assert!(matches!(filtered.get_artifact("PowerTools", "1.2.0", "a").await, Err(StorageError::NotFound(_))));
+        assert!(matches!(filtered.get_artifact("Untrusted", "0.1.0", "d").await, Err(StorageError::NotFound(_))));
+        // the hash of a denied version doesn't pass under an allowed one
+        assert!(matches!(filtered.get_artifact("PowerTools", "1.1.0", "a").await, Err(StorageError::NotFound(_))));
+        // unlisted names and versions are rejected without listing the inner store again right away
+        assert!(matches!(filtered.get_artifact("TabMaster", "1.9.0", "c").await, Err(StorageError::NotFound(_))));
+        assert!(matches!(filtered.get_artifact("Bogus", "1.0.0", "").await, Err(StorageError::NotFound(_))));
+        assert!(matches!(filtered.get_image("Bogus").await, Err(StorageError::NotFound(_))));
+        assert_eq!(inner_listings(&filtered), 1);
+    }
+
+    fn inner_listings(filtered: &FilteredStorage<StaticStorage>) -> usize {
+        filtered.inner.listings.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    #[actix_web::test]
+    async fn relist_unknown_artifacts() {
+        let inner = StaticStorage::new(vec![
+            plugin("PowerTools", "NGnius", &[], &[("1.1.0", "a")]),
+        ]);
+        let deny = FilterRules {
+            names: vec!["Untrusted".to_owned()],
+            ..Default::default()
+        };
+        let filtered = FilteredStorage::new(inner, FilterRules::default(), deny);
+        // downloads before the first listing list the inner store
+        assert!(filtered.get_artifact("PowerTools", "1.1.0", "a").await.is_ok());
+        assert_eq!(inner_listings(&filtered), 1);
+        // denied versions are rejected by the rules alone
+        assert!(matches!(filtered.get_artifact("Untrusted", "0.1.0", "b").await, Err(StorageError::NotFound(_))));
+        assert_eq!(inner_listings(&filtered), 1);
+
+        // a plugin added since the last listing is looked up once that is old enough
+        assert!(matches!(filtered.get_artifact("New", "0.1.0", "c").await, Err(StorageError::NotFound(_))));
+        assert_eq!(inner_listings(&filtered), 1);
+        *filtered.listed_at.lock().unwrap() = Instant::now().checked_sub(RELIST_INTERVAL * 2);
+        assert!(matches!(filtered.get_artifact("New", "0.1.0", "c").await, Err(StorageError::NotFound(_))));
+        assert_eq!(inner_listings(&filtered), 2);
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Reject anything that could escape its parent directory when used in a file name
+pub fn safe_file_stem(name: &str) -> std::io::Result<&str> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid file name `{}`", name)))
+    } else {
+        Ok(name)
+    }
+}
+
+/// Write to a temporary file next to `path` and then move it into place,
+/// so that readers never see a partially written file.
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = write_tmp_file(path, data)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Like `write_file_atomic`, but fails with `AlreadyExists` instead of replacing an existing file.
+pub fn create_file_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = write_tmp_file(path, data)?;
+    let result = std::fs::hard_link(&tmp_path, path);
+    let _ = std::fs::remove_file(&tmp_path);
+    result
+}
+
+/// A file which is written in parts next to `path` and only moved into place by `finish`.
+/// It is removed again if dropped before then.
+pub struct PartialFile {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: File,
+    finished: bool,
+}
+
+impl PartialFile {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        let (tmp_path, file) = create_tmp_file(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            tmp_path,
+            file,
+            finished: false,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.file.sync_all()?;
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+fn create_tmp_file(path: &Path) -> std::io::Result<(PathBuf, File)> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension(format!("{}-{}.part", std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let file = File::create(&tmp_path)?;
+    Ok((tmp_path, file))
+}
+
+fn write_tmp_file(path: &Path, data: &[u8]) -> std::io::Result<PathBuf> {
+    let (tmp_path, mut file) = create_tmp_file(path)?;
+    if let Err(e) = file.write_all(data).and_then(|_| file.sync_all()) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(tmp_path)
+}
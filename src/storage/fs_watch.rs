@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Plugins which need to be re-read since the last scan
+pub enum Dirty {
+    All,
+    Plugins(HashSet<String>),
+}
+
+impl Dirty {
+    fn mark(&mut self, plugin_name: String) {
+        if let Self::Plugins(names) = self {
+            names.insert(plugin_name);
+        }
+    }
+}
+
+/// Watches a `plugins/` folder and tracks which plugin folders changed
+pub struct PluginWatcher {
+    dirty: Arc<Mutex<Dirty>>,
+    _watcher: RecommendedWatcher,
+}
+
+impl PluginWatcher {
+    pub fn new(plugins_path: &Path) -> notify::Result<Self> {
+        let plugins_path = plugins_path.canonicalize()?;
+        let dirty = Arc::new(Mutex::new(Dirty::All));
+        let event_dirty = dirty.clone();
+        let event_root = plugins_path.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let mut lock = event_dirty.lock().expect("Failed to acquire dirty plugins lock");
+            match event {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        return;
+                    }
+                    if event.need_rescan() {
+                        *lock = Dirty::All;
+                        return;
+                    }
+                    for path in event.paths {
+                        match plugin_name_of(&event_root, &path) {
+                            Some(name) => lock.mark(name),
+                            None => *lock = Dirty::All,
+                        }
+                    }
+                },
+                Err(e) => {
+                    log::error!("Plugins folder watch error, rescanning everything: {}", e);
+                    *lock = Dirty::All;
+                }
+            }
+        })?;
+        watcher.watch(&plugins_path, RecursiveMode::Recursive)?;
+        log::info!("Watching {} for changes", plugins_path.display());
+        Ok(Self {
+            dirty,
+            _watcher: watcher,
+        })
+    }
+
+    /// Get the plugins changed since the last call
+    pub fn take_dirty(&self) -> Dirty {
+        let mut lock = self.dirty.lock().expect("Failed to acquire dirty plugins lock");
+        std::mem::replace(&mut *lock, Dirty::Plugins(HashSet::new()))
+    }
+
+    /// Re-read a plugin on the next scan
+    pub fn mark(&self, plugin_name: String) {
+        self.dirty.lock().expect("Failed to acquire dirty plugins lock").mark(plugin_name);
+    }
+
+    /// Re-read everything on the next scan, e.g. after a failed scan
+    pub fn mark_all(&self) {
+        *self.dirty.lock().expect("Failed to acquire dirty plugins lock") = Dirty::All;
+    }
+}
+
+/// The name of the plugin folder containing `path`, or `None` for changes to the plugins folder itself
+fn plugin_name_of(plugins_path: &Path, path: &Path) -> Option<String> {
+    let relative: PathBuf = path.strip_prefix(plugins_path).ok()?.to_owned();
+    match relative.components().next() {
+        Some(Component::Normal(name)) => Some(name.to_string_lossy().into_owned()),
+        _ => None,
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::UNIX_EPOCH;
+
+use serde::{Serialize, Deserialize};
+
+use super::fs_util::write_file_atomic;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct FileStamp {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct IndexEntry {
+    #[serde(flatten)]
+    stamp: FileStamp,
+    hash: String,
+}
+
+/// Persistent cache of artifact sha256 hashes, keyed by path, size and modification time
+pub struct HashIndex {
+    path: PathBuf,
+    entries: RwLock<HashMap<PathBuf, IndexEntry>>,
+    dirty: AtomicBool,
+}
+
+impl HashIndex {
+    pub fn load(path: PathBuf) -> Self {
+        let entries: HashMap<PathBuf, IndexEntry> = match File::open(&path) {
+            Ok(file) => match serde_json::from_reader(file) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::error!("`{}` JSON err, rebuilding hash index: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                log::error!("Failed to open hash index `{}`: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+        log::info!("Loaded {} artifact hashes from {}", entries.len(), path.display());
+        Self {
+            path,
+            entries: RwLock::new(entries),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the sha256 hash of a file, only reading the file when it changed since it was last hashed
+    pub fn hash(&self, file: &Path) -> std::io::Result<String> {
+        let stamp = FileStamp::of(file)?;
+        if let Some(entry) = self.entries.read().expect("Failed to acquire hash index read lock").get(file) {
+            if entry.stamp == stamp {
+                return Ok(entry.hash.clone());
+            }
+        }
+        log::debug!("Hashing {}", file.display());
+        let hash = sha256::try_digest(file)?;
+        self.insert_stamped(file, stamp, hash.clone());
+        Ok(hash)
+    }
+
+    /// Record the hash of a file which was just written
+    pub fn insert(&self, file: &Path, hash: String) -> std::io::Result<()> {
+        let stamp = FileStamp::of(file)?;
+        self.insert_stamped(file, stamp, hash);
+        Ok(())
+    }
+
+    fn insert_stamped(&self, file: &Path, stamp: FileStamp, hash: String) {
+        self.entries.write()
+            .expect("Failed to acquire hash index write lock")
+            .insert(file.to_owned(), IndexEntry { stamp, hash });
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Write the index to disk if it changed, dropping entries for files which no longer exist
+    pub fn flush(&self) -> std::io::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let json = {
+            let mut lock = self.entries.write().expect("Failed to acquire hash index write lock");
+            lock.retain(|file, _| file.is_file());
+            serde_json::to_vec(&*lock)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        };
+        let result = write_file_atomic(&self.path, &json);
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn rehash_only_changed_files() {
+        let dir = TempDir::new("hash-index");
+        let artifact = dir.join("1.0.0.zip");
+        std::fs::write(&artifact, b"first").unwrap();
+        let index = HashIndex::load(dir.join("hashes.json"));
+        let first = index.hash(&artifact).unwrap();
+        assert_eq!(first, sha256::digest("first"));
+        index.flush().unwrap();
+
+        let index = HashIndex::load(dir.join("hashes.json"));
+        assert_eq!(index.hash(&artifact).unwrap(), first);
+        std::fs::write(&artifact, b"second!").unwrap();
+        assert_eq!(index.hash(&artifact).unwrap(), sha256::digest("second!"));
+    }
+}
@@ -1,23 +1,150 @@
+use async_trait::async_trait;
+
+use super::StorageError;
+
+/// A new plugin version, as received by the upload API
+#[derive(Clone)]
+pub struct PluginUpload {
+    pub artifact: bytes::Bytes,
+    /// `plugin.json` contents, required when publishing the first version of a plugin
+    pub metadata: Option<bytes::Bytes>,
+    pub image: Option<bytes::Bytes>,
+}
+
+/// Administrative change to an existing plugin version
+#[derive(Clone, Debug)]
+pub enum VersionUpdate {
+    /// Remove from the plugin list, but keep the artifact downloadable
+    Hide,
+    Unhide,
+    /// Keep listing the version, but flag it as retracted
+    Yank(Option<String>),
+    Unyank,
+}
+
+/// An artifact or image returned by a store
+#[derive(Debug)]
+pub enum StorageFile {
+    /// File on disk, streamed to the client in chunks
+    Path(std::path::PathBuf),
+    /// Data already held in memory
+    Bytes(bytes::Bytes),
+    /// Data still arriving, e.g. from an upstream store, which can only be sent once
+    Stream(FileStream),
+}
+
+pub struct FileStream {
+    /// Total size, if known in advance
+    pub length: Option<u64>,
+    pub chunks: futures_util::stream::BoxStream<'static, Result<bytes::Bytes, StorageError>>,
+}
+
+impl std::fmt::Debug for FileStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileStream").field("length", &self.length).finish_non_exhaustive()
+    }
+}
+
+/// A plugin artifact, along with the sha256 hash of its contents
+#[derive(Debug)]
+pub struct Artifact {
+    pub file: StorageFile,
+    pub hash: String,
+}
+
+/// Health of a store, ordered from best to worst
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    /// Usable, but possibly serving incomplete or outdated data
+    Degraded,
+    /// Unusable
+    Failing,
+}
+
+/// Health report for a store and, for wrappers, the stores inside it
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct StoreHealth {
+    pub store: String,
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// How long the health check (or the last request it reports on) took
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<StoreHealth>,
+}
+
+impl StoreHealth {
+    pub fn new(store: impl Into<String>, status: HealthStatus, last_error: Option<String>, latency: std::time::Duration) -> Self {
+        Self {
+            store: store.into(),
+            status,
+            last_error,
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_children(mut self, children: Vec<StoreHealth>) -> Self {
+        self.children = children;
+        self
+    }
+}
+
+#[async_trait]
 pub trait IStorage: Send + Sync {
-    fn plugins(&self) -> decky_api::StorePluginList;
+    async fn plugins(&self) -> Result<decky_api::StorePluginList, StorageError>;
+
+    /// Get a version's artifact, which is `NotFound` unless its contents have the requested hash
+    async fn get_artifact(&self, _name: &str, _version: &str, _hash: &str) -> Result<Artifact, StorageError> {
+        Err(StorageError::Unsupported("Artifact downloading not supported".to_owned()))
+    }
+
+    /// Record a download of an artifact returned by `get_artifact`
+    async fn count_download(&self, _name: &str, _version: &str, _hash: &str) {}
 
-    fn get_artifact(&self, _name: &str, _version: &str, _hash: &str) -> Result<bytes::Bytes, std::io::Error> {
-        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Artifact downloading not supported"))
+    async fn get_image(&self, _name: &str) -> Result<StorageFile, StorageError> {
+        Err(StorageError::Unsupported("Image downloading not supported".to_owned()))
     }
 
-    fn get_image(&self, _name: &str) -> Result<bytes::Bytes, std::io::Error> {
-        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Image downloading not supported"))
+    async fn get_statistics(&self) -> Result<std::collections::HashMap<String, u64>, StorageError> {
+        Ok(std::collections::HashMap::with_capacity(0))
     }
 
-    fn get_statistics(&self) -> std::collections::HashMap<String, u64> {
-        std::collections::HashMap::with_capacity(0)
+    async fn publish(&self, _name: &str, _version: &str, _upload: PluginUpload) -> Result<decky_api::StorePluginVersion, StorageError> {
+        Err(StorageError::Unsupported("Publishing not supported".to_owned()))
+    }
+
+    async fn delete_version(&self, _name: &str, _version: &str) -> Result<(), StorageError> {
+        Err(StorageError::Unsupported("Deleting not supported".to_owned()))
+    }
+
+    async fn update_version(&self, _name: &str, _version: &str, _update: VersionUpdate) -> Result<(), StorageError> {
+        Err(StorageError::Unsupported("Updating versions not supported".to_owned()))
+    }
+
+    /// Check whether the store is usable, including any stores it wraps
+    async fn health(&self) -> StoreHealth {
+        let start = std::time::Instant::now();
+        let (status, last_error) = match self.plugins().await {
+            Ok(_) => (HealthStatus::Healthy, None),
+            Err(e) => (HealthStatus::Failing, Some(e.to_string())),
+        };
+        StoreHealth::new("storage", status, last_error, start.elapsed())
     }
 }
 
 pub struct EmptyStorage;
 
+#[async_trait]
 impl IStorage for EmptyStorage {
-    fn plugins(&self) -> decky_api::StorePluginList {
-        Vec::new()
+    async fn plugins(&self) -> Result<decky_api::StorePluginList, StorageError> {
+        Ok(Vec::new())
+    }
+
+    async fn health(&self) -> StoreHealth {
+        StoreHealth::new("empty", HealthStatus::Healthy, None, std::time::Duration::ZERO)
     }
 }
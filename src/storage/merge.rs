@@ -1,10 +1,34 @@
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use decky_api::{StorePluginList, StorePlugin};
+use async_trait::async_trait;
+use decky_api::{StorePlugin, StorePluginList, StorePluginVersion};
+use futures_util::future::join_all;
+use serde::Deserialize;
 
-use super::IStorage;
+use super::filesystem::id_from_name;
+use super::{Artifact, HealthStatus, IStorage, PluginUpload, StorageError, StorageFile, StoreHealth, VersionUpdate};
+use super::version_order::{compare_versions, sort_newest_first};
 
+/// Minimum time between listings caused by downloads of versions which weren't in the last listing
+const RELIST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How to combine a plugin offered by more than one store
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergePolicy {
+    /// List the plugin exactly as the first store offering it does
+    Priority,
+    /// Combine the versions of every store, keeping the first store's copy of a duplicated hash or version name.
+    /// Description, author and image come from the first store.
+    #[default]
+    Union,
+    /// List the plugin as the store with the newest version does
+    Newest,
+}
+
+#[derive(Clone, Copy)]
 struct StoreIndex(usize);
 #[derive(Hash, Eq, PartialEq)]
 struct StoreName(String);
@@ -16,18 +40,80 @@ struct HashablePluginVersion {
     hash: String,
 }
 
+/// Each store's entry for every plugin, with plugins in the order they were first seen
+#[derive(Default)]
+struct MergedPlugins {
+    positions: HashMap<StoreName, usize>,
+    candidates: Vec<Vec<(StoreIndex, StorePlugin)>>,
+}
+
+/// A plugin after applying the merge policy, with the store each version comes from
+struct ResolvedPlugin {
+    plugin: StorePlugin,
+    version_stores: Vec<StoreIndex>,
+    /// Stores to get the image from, in order of preference
+    image_stores: Vec<StoreIndex>,
+}
+
 pub struct MergedStorage<S: AsRef<dyn IStorage> + Send + Sync> {
     stores: Vec<S>,
+    policy: MergePolicy,
     store_artifact_map: RwLock<HashMap<HashablePluginVersion, StoreIndex>>,
     store_image_map: RwLock<HashMap<StoreName, Vec<StoreIndex>>>,
+    /// When the stores were last listed successfully
+    listed_at: Mutex<Option<Instant>>,
 }
 
 impl<S: AsRef<dyn IStorage> + Send + Sync> MergedStorage<S> {
     pub fn new(inner: Vec<S>) -> Self {
         Self {
             stores: inner,
+            policy: MergePolicy::default(),
             store_artifact_map: RwLock::new(HashMap::new()),
             store_image_map: RwLock::new(HashMap::new()),
+            listed_at: Mutex::new(None),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: MergePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Combine the results of applying an operation to every store, succeeding if at least one store knows the version
+    fn owners_result(results: Vec<Result<(), StorageError>>) -> Result<(), StorageError> {
+        let mut found = false;
+        for result in results {
+            match result {
+                Ok(()) => found = true,
+                Err(StorageError::Unsupported(_) | StorageError::NotFound(_)) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(StorageError::NotFound("Plugin version does not exist in any store".to_owned()))
+        }
+    }
+
+    /// Keep the results of the stores which answered, only failing if every store failed
+    fn available<T>(results: Vec<Result<T, StorageError>>) -> Result<Vec<(StoreIndex, T)>, StorageError> {
+        let total = results.len();
+        let mut first_error = None;
+        let mut available = Vec::with_capacity(total);
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => available.push((StoreIndex(index), value)),
+                Err(e) => {
+                    log::error!("Store #{} failed, merging the others: {}", index, e);
+                    first_error.get_or_insert(e);
+                },
+            }
+        }
+        match first_error {
+            Some(e) if available.is_empty() => Err(e),
+            _ => Ok(available),
         }
     }
 
@@ -36,25 +122,126 @@ impl<S: AsRef<dyn IStorage> + Send + Sync> MergedStorage<S> {
         self
     }*/
 
-    fn map_to_vec(plugins: HashMap<StoreName, StorePlugin>) -> StorePluginList {
-        let mut result = Vec::with_capacity(plugins.len());
-        for (_, val) in plugins {
-            result.push(val);
+    fn merge_plugins_into(dest: &mut MergedPlugins, index: StoreIndex, source: StorePluginList) {
+        for plugin in source {
+            let store_name = StoreName(plugin.name.clone());
+            if let Some(&position) = dest.positions.get(&store_name) {
+                // same name as an existing plugin, resolved later by the merge policy
+                dest.candidates[position].push((index, plugin));
+            } else {
+                // create new plugin entry if not
+                dest.positions.insert(store_name, dest.candidates.len());
+                dest.candidates.push(vec![(index, plugin)]);
+            }
         }
-        result
     }
 
-    fn merge_plugins_into(dest: &mut HashMap<StoreName, StorePlugin>, source: StorePluginList) {
-        for mut plugin in source {
-            let store_name = StoreName(plugin.name.clone());
-            if let Some(existing_plugin) = dest.get_mut(&store_name) {
-                // combine versions if the plugin has the same name as an existing one
-                existing_plugin.versions.append(&mut plugin.versions);
+    fn resolve(&self, mut candidates: Vec<(StoreIndex, StorePlugin)>) -> ResolvedPlugin {
+        let image_stores: Vec<StoreIndex> = candidates.iter().map(|(index, _)| *index).collect();
+        let winner = match self.policy {
+            MergePolicy::Priority | MergePolicy::Union => 0,
+            MergePolicy::Newest => {
+                let mut winner = 0;
+                for (i, (_, plugin)) in candidates.iter().enumerate().skip(1) {
+                    let newest = plugin.versions.first().map(|v| v.name.as_str());
+                    let best = candidates[winner].1.versions.first().map(|v| v.name.as_str());
+                    let is_newer = match (newest, best) {
+                        (Some(newest), Some(best)) => compare_versions(newest, best).is_gt(),
+                        (Some(_), None) => true,
+                        (None, _) => false,
+                    };
+                    if is_newer {
+                        winner = i;
+                    }
+                }
+                winner
+            },
+        };
+        let (winner_index, mut plugin) = candidates.remove(winner);
+        let mut versions: Vec<(StoreIndex, StorePluginVersion)> = plugin.versions.drain(..)
+            .map(|version| (winner_index, version))
+            .collect();
+        if self.policy == MergePolicy::Union {
+            let mut hashes: HashSet<String> = versions.iter().map(|(_, v)| v.hash.clone()).collect();
+            let mut names: HashSet<String> = versions.iter().map(|(_, v)| v.name.clone()).collect();
+            for (index, other) in candidates {
+                for version in other.versions {
+                    if hashes.contains(&version.hash) || names.contains(&version.name) {
+                        log::debug!("Dropping duplicate {} {} from store #{}", other.name, version.name, index.0);
+                        continue;
+                    }
+                    hashes.insert(version.hash.clone());
+                    names.insert(version.name.clone());
+                    versions.push((index, version));
+                }
+            }
+            versions.sort_by(|(_, a), (_, b)| compare_versions(&b.name, &a.name));
+        }
+        let (version_stores, versions) = versions.into_iter().unzip();
+        plugin.versions = versions;
+        ResolvedPlugin {
+            plugin,
+            version_stores,
+            image_stores: if self.policy == MergePolicy::Union {
+                image_stores
             } else {
-                // create new plugin entry if not
-                dest.insert(store_name, plugin);
+                vec![winner_index]
+            },
+        }
+    }
+
+    /// Keep the ids of plugins first seen in the first store, and derive the others from that store and the name,
+    /// so an id doesn't change when other plugins are added or removed
+    fn remap_ids(plugins: &mut StorePluginList, first_stores: &[StoreIndex]) {
+        let mut used = HashSet::with_capacity(plugins.len());
+        let mut remapped = Vec::new();
+        for (position, (plugin, store)) in plugins.iter().zip(first_stores).enumerate() {
+            if store.0 != 0 || !used.insert(plugin.id) {
+                remapped.push(position);
             }
         }
+        for position in remapped {
+            let plugin = &mut plugins[position];
+            let mut id = id_from_name(&format!("{}:{}", first_stores[position].0, plugin.name));
+            // only a hash collision moves it further
+            while !used.insert(id) {
+                id = (id + 1) & 0x7fff_ffff;
+            }
+            log::debug!("Remapping id {} of {} to {}", plugin.id, plugin.name, id);
+            plugin.id = id;
+        }
+    }
+
+    /// The store which listed a version, listing the stores again if it may have been added since
+    async fn owner(&self, name: &str, version: &str, hash: &str) -> Result<Option<&S>, StorageError> {
+        let key = HashablePluginVersion {
+            plugin_name: name.to_owned(),
+            version_name: version.to_owned(),
+            hash: hash.to_owned(),
+        };
+        let find = || self.store_artifact_map.read()
+            .expect("Failed to acquire store_artifact_map read lock")
+            .get(&key)
+            .and_then(|index| self.stores.get(index.0));
+        if let Some(store) = find() {
+            return Ok(Some(store));
+        }
+        let listed_at = *self.listed_at.lock().expect("Failed to acquire merge listing time lock");
+        if listed_at.map(|at| at.elapsed() > RELIST_INTERVAL).unwrap_or(true) {
+            self.plugins().await?;
+        }
+        Ok(find())
+    }
+
+    /// Failing only when every store is failing, since the others can still serve plugins
+    fn merged_status(children: &[StoreHealth]) -> HealthStatus {
+        if !children.is_empty() && children.iter().all(|child| child.status == HealthStatus::Failing) {
+            HealthStatus::Failing
+        } else if children.iter().any(|child| child.status != HealthStatus::Healthy) {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        }
     }
 
     fn merge_statistics_into(dest: &mut HashMap<String, u64>, source: HashMap<String, u64>) {
@@ -70,80 +257,244 @@ impl<S: AsRef<dyn IStorage> + Send + Sync> MergedStorage<S> {
     }
 }
 
+#[async_trait]
 impl<S: AsRef<dyn IStorage> + Send + Sync> IStorage for MergedStorage<S> {
-    fn plugins(&self) -> StorePluginList {
-        let mut merged_plugins = HashMap::new();
+    async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        let mut merged_plugins = MergedPlugins::default();
+        let lists = Self::available(join_all(self.stores.iter().map(|store| store.as_ref().plugins())).await)?;
+        for (index, mut plugins) in lists {
+            for plugin in &mut plugins {
+                sort_newest_first(&mut plugin.versions);
+            }
+            Self::merge_plugins_into(&mut merged_plugins, index, plugins);
+        }
         log::debug!("Acquiring store map write locks");
         let mut arti_lock = self.store_artifact_map.write().expect("Failed to acquire store_artifact_map write lock");
         let mut img_lock = self.store_image_map.write().expect("Failed to acquire store_image_map write lock");
-        for (index, store) in self.stores.iter().enumerate() {
-            let plugins = store.as_ref().plugins();
-            // re-build store mappins
-            for plugin in &plugins {
-                for version in &plugin.versions {
-                    let hashable_ver = HashablePluginVersion {
-                        plugin_name: plugin.name.clone(),
-                        version_name: version.name.clone(),
-                        hash: version.hash.clone(),
-                    };
-                    arti_lock.insert(hashable_ver, StoreIndex(index));
-                }
-                let store_name = StoreName(plugin.name.clone());
-                if let Some(stores) = img_lock.get_mut(&store_name) {
-                    stores.push(StoreIndex(index));
-                } else {
-                    img_lock.insert(store_name, vec![StoreIndex(index)]);
-                }
+        arti_lock.clear();
+        img_lock.clear();
+        let mut plugins = Vec::with_capacity(merged_plugins.candidates.len());
+        let mut first_stores = Vec::with_capacity(merged_plugins.candidates.len());
+        for candidates in merged_plugins.candidates {
+            first_stores.push(candidates[0].0);
+            let resolved = self.resolve(candidates);
+            // re-build store mappings
+            for (version, index) in resolved.plugin.versions.iter().zip(resolved.version_stores) {
+                let hashable_ver = HashablePluginVersion {
+                    plugin_name: resolved.plugin.name.clone(),
+                    version_name: version.name.clone(),
+                    hash: version.hash.clone(),
+                };
+                arti_lock.insert(hashable_ver, index);
             }
-            Self::merge_plugins_into(&mut merged_plugins, plugins);
+            img_lock.insert(StoreName(resolved.plugin.name.clone()), resolved.image_stores);
+            plugins.push(resolved.plugin);
         }
-        Self::map_to_vec(merged_plugins)
+        drop(arti_lock);
+        drop(img_lock);
+        Self::remap_ids(&mut plugins, &first_stores);
+        *self.listed_at.lock().expect("Failed to acquire merge listing time lock") = Some(Instant::now());
+        Ok(plugins)
     }
 
-    fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<bytes::Bytes, std::io::Error> {
-        log::debug!("Acquiring store_artifact_map read lock");
-        let lock = self.store_artifact_map.read().expect("Failed to acquire store_artifact_map read lock");
-        if let Some(index) = lock.get(&HashablePluginVersion {
-            plugin_name: name.to_owned(),
-            version_name: version.to_owned(),
-            hash: hash.to_owned(),
-        }) {
-            if let Some(store) = self.stores.get(index.0) {
-                store.as_ref().get_artifact(name, version, hash)
-            } else {
-                Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Store index {} does not exist", index.0)))
-            }
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Plugin version does not exist in any store"))
+    async fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, StorageError> {
+        // only listed versions are served, since the store they come from is only known from the listing
+        match self.owner(name, version, hash).await? {
+            Some(store) => store.as_ref().get_artifact(name, version, hash).await,
+            None => Err(StorageError::NotFound("Plugin version is not listed by any store".to_owned())),
+        }
+    }
+
+    async fn count_download(&self, name: &str, version: &str, hash: &str) {
+        match self.owner(name, version, hash).await {
+            Ok(Some(store)) => store.as_ref().count_download(name, version, hash).await,
+            Ok(None) => log::debug!("Not counting download of unlisted {} v{}", name, version),
+            Err(e) => log::error!("Failed to find the store of {} v{} to count a download: {}", name, version, e),
         }
     }
 
-    fn get_image(&self, name: &str) -> Result<bytes::Bytes, std::io::Error> {
+    async fn get_image(&self, name: &str) -> Result<StorageFile, StorageError> {
         log::debug!("Acquiring store_image_map read lock");
-        let lock = self.store_image_map.read().expect("Failed to acquire store_image_map read lock");
-        if let Some(indices) = lock.get(&StoreName(name.to_owned())) {
+        let indices = self.store_image_map.read()
+            .expect("Failed to acquire store_image_map read lock")
+            .get(&StoreName(name.to_owned()))
+            .cloned();
+        if let Some(indices) = indices {
+            let mut last_error = None;
             for index in indices {
                 if let Some(store) = self.stores.get(index.0) {
-                    match store.as_ref().get_image(name) {
+                    match store.as_ref().get_image(name).await {
                         Ok(img) => return Ok(img),
-                        Err(e) => log::error!("Error retrieving image from store #{}: {}", index.0, e),
+                        Err(e) => {
+                            log::error!("Error retrieving image from store #{}: {}", index.0, e);
+                            last_error = Some(e);
+                        },
                     }
                 }
             }
-
-            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Stores do not exist for that plugin"))
+            Err(last_error.unwrap_or_else(|| StorageError::NotFound("Stores do not exist for that plugin".to_owned())))
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Plugin does not exist in any store"))
+            Err(StorageError::NotFound("Plugin does not exist in any store".to_owned()))
         }
     }
 
-    fn get_statistics(&self) -> std::collections::HashMap<String, u64> {
+    async fn get_statistics(&self) -> Result<std::collections::HashMap<String, u64>, StorageError> {
         let mut stats = HashMap::new();
-        for store in &self.stores {
-            let new_stats = store.as_ref().get_statistics();
+        for (_, new_stats) in Self::available(join_all(self.stores.iter().map(|store| store.as_ref().get_statistics())).await)? {
             Self::merge_statistics_into(&mut stats, new_stats);
         }
 
-        stats
+        Ok(stats)
+    }
+
+    async fn publish(&self, name: &str, version: &str, upload: PluginUpload) -> Result<decky_api::StorePluginVersion, StorageError> {
+        // publish to the first store which supports it
+        for (index, store) in self.stores.iter().enumerate() {
+            match store.as_ref().publish(name, version, upload.clone()).await {
+                Err(StorageError::Unsupported(_)) => continue,
+                Err(e) => return Err(e),
+                Ok(published) => {
+                    self.store_artifact_map.write()
+                        .expect("Failed to acquire store_artifact_map write lock")
+                        .insert(HashablePluginVersion {
+                            plugin_name: name.to_owned(),
+                            version_name: published.name.clone(),
+                            hash: published.hash.clone(),
+                        }, StoreIndex(index));
+                    return Ok(published);
+                }
+            }
+        }
+        Err(StorageError::Unsupported("No store supports publishing".to_owned()))
+    }
+
+    async fn delete_version(&self, name: &str, version: &str) -> Result<(), StorageError> {
+        Self::owners_result(join_all(self.stores.iter().map(|store| store.as_ref().delete_version(name, version))).await)
+    }
+
+    async fn update_version(&self, name: &str, version: &str, update: VersionUpdate) -> Result<(), StorageError> {
+        Self::owners_result(join_all(self.stores.iter().map(|store| store.as_ref().update_version(name, version, update.clone()))).await)
+    }
+
+    async fn health(&self) -> StoreHealth {
+        let start = std::time::Instant::now();
+        let children: Vec<StoreHealth> = join_all(self.stores.iter().map(|store| store.as_ref().health())).await;
+        let last_error = children.iter()
+            .find_map(|child| child.last_error.as_ref().map(|e| format!("{}: {}", child.store, e)));
+        StoreHealth::new("merge", Self::merged_status(&children), last_error, start.elapsed())
+            .with_children(children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::EmptyStorage;
+    use crate::test_util::{self, StaticStorage};
+
+    struct BrokenStorage;
+
+    #[async_trait]
+    impl IStorage for BrokenStorage {
+        async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+            Err(StorageError::Unavailable("Connection refused".to_owned()))
+        }
+
+        async fn health(&self) -> StoreHealth {
+            StoreHealth::new("broken", HealthStatus::Failing, Some("Connection refused".to_owned()), std::time::Duration::ZERO)
+        }
+    }
+
+    fn plugin(id: usize, name: &str, author: &str, versions: &[(&str, &str)]) -> StorePlugin {
+        StorePlugin {
+            id,
+            author: author.to_owned(),
+            ..test_util::plugin(name, versions)
+        }
+    }
+
+    fn stores() -> (StaticStorage, StaticStorage) {
+        let origin = StaticStorage::new(vec![
+            plugin(1, "Foo", "origin", &[("1.9.0", "a"), ("1.0.0", "b")]),
+            plugin(2, "Bar", "origin", &[("1.0.0", "c")]),
+        ]);
+        let mirror = StaticStorage::new(vec![
+            plugin(1, "Foo", "mirror", &[("1.10.0", "d"), ("1.9.0", "a")]),
+            plugin(2, "Baz", "mirror", &[("0.1.0", "e")]),
+        ]);
+        (origin, mirror)
+    }
+
+    async fn merged(policy: MergePolicy) -> StorePluginList {
+        let (origin, mirror) = stores();
+        MergedStorage::new(vec![origin, mirror]).with_policy(policy).plugins().await.unwrap()
+    }
+
+    fn versions(plugin: &StorePlugin) -> Vec<&str> {
+        plugin.versions.iter().map(|v| v.name.as_str()).collect()
+    }
+
+    #[actix_web::test]
+    async fn merge_policies() {
+        let plugins = merged(MergePolicy::Union).await;
+        assert_eq!(versions(&plugins[0]), vec!["1.10.0", "1.9.0", "1.0.0"]);
+        assert_eq!(plugins[0].author, "origin");
+        // plugins only in the mirror get ids from their name, whether they clash or not
+        let baz_id = id_from_name("1:Baz");
+        let ids: Vec<usize> = plugins.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1, 2, baz_id]);
+        let mirror = StaticStorage::new(vec![plugin(3, "Baz", "mirror", &[("0.1.0", "e")])]);
+        let plugins = MergedStorage::new(vec![StaticStorage::new(Vec::new()), mirror]).plugins().await.unwrap();
+        assert_eq!(plugins[0].id, baz_id);
+
+        let plugins = merged(MergePolicy::Priority).await;
+        assert_eq!(versions(&plugins[0]), vec!["1.9.0", "1.0.0"]);
+        assert_eq!(plugins[0].author, "origin");
+
+        let plugins = merged(MergePolicy::Newest).await;
+        assert_eq!(versions(&plugins[0]), vec!["1.10.0", "1.9.0"]);
+        assert_eq!(plugins[0].author, "mirror");
+    }
+
+    #[actix_web::test]
+    async fn merged_health() {
+        let healthy: Box<dyn IStorage> = Box::new(EmptyStorage);
+        let broken: Box<dyn IStorage> = Box::new(BrokenStorage);
+        let health = MergedStorage::new(vec![healthy, broken]).health().await;
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(health.children.len(), 2);
+        assert_eq!(health.last_error.as_deref(), Some("broken: Connection refused"));
+
+        let broken: Box<dyn IStorage> = Box::new(BrokenStorage);
+        assert_eq!(MergedStorage::new(vec![broken]).health().await.status, HealthStatus::Failing);
+
+        // listing fails only when no store can list anything
+        let healthy: Box<dyn IStorage> = Box::new(EmptyStorage);
+        let broken: Box<dyn IStorage> = Box::new(BrokenStorage);
+        assert!(MergedStorage::new(vec![healthy, broken]).plugins().await.is_ok());
+        let broken: Box<dyn IStorage> = Box::new(BrokenStorage);
+        assert!(matches!(MergedStorage::new(vec![broken]).plugins().await, Err(StorageError::Unavailable(_))));
+    }
+
+    #[actix_web::test]
+    async fn owned_downloads() {
+        let (origin, mirror) = stores();
+        let merged = MergedStorage::new(vec![origin, mirror]);
+        let downloads = |merged: &MergedStorage<StaticStorage>| merged.stores.iter()
+            .map(|store| store.downloads.load(std::sync::atomic::Ordering::SeqCst))
+            .collect::<Vec<_>>();
+
+        // the first download lists the stores to find the one which has it
+        assert!(merged.get_artifact("Baz", "0.1.0", "e").await.is_ok());
+        merged.count_download("Baz", "0.1.0", "e").await;
+        merged.count_download("Foo", "1.9.0", "a").await;
+        assert_eq!(downloads(&merged), vec![1, 1]);
+
+        // versions which aren't listed aren't tried in every store
+        assert!(matches!(merged.get_artifact("Baz", "0.2.0", "f").await, Err(StorageError::NotFound(_))));
+        assert!(matches!(merged.get_artifact("Baz", "0.1.0", "a").await, Err(StorageError::NotFound(_))));
+        merged.count_download("Baz", "0.2.0", "f").await;
+        assert_eq!(downloads(&merged), vec![1, 1]);
+        assert_eq!(merged.stores[0].listings.load(std::sync::atomic::Ordering::SeqCst), 1);
     }
 }
@@ -1,11 +1,23 @@
 mod cache;
+mod error;
 mod filesystem;
+mod filter;
+mod fs_util;
+mod fs_watch;
+mod hash_index;
 mod interface;
 mod merge;
+mod overrides;
 mod proxy;
+mod stats_journal;
+mod version_order;
+mod zip_metadata;
 
 pub use cache::CachedStorage;
+pub use error::StorageError;
 pub use filesystem::FileStorage;
-pub use interface::{IStorage, EmptyStorage};
-pub use merge::MergedStorage;
+pub use filter::{FilteredStorage, FilterRules};
+pub use interface::{IStorage, Artifact, EmptyStorage, FileStream, HealthStatus, PluginUpload, StorageFile, StoreHealth, VersionUpdate};
+pub use merge::{MergedStorage, MergePolicy};
+pub use overrides::{OverrideStorage, Overrides};
 pub use proxy::ProxiedStorage;
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use decky_api::{StorePlugin, StorePluginList};
+use serde::Deserialize;
+
+use super::{Artifact, HealthStatus, IStorage, PluginUpload, StorageError, StorageFile, StoreHealth, VersionUpdate};
+
+/// Contents of an overrides file, keyed by plugin name
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    #[serde(default)]
+    pub plugins: HashMap<String, PluginOverride>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginOverride {
+    pub id: Option<usize>,
+    /// Displayed author
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub add_tags: Vec<String>,
+    pub remove_tags: Vec<String>,
+    /// Image URL, or a PNG file relative to the overrides file
+    pub image: Option<String>,
+}
+
+impl PluginOverride {
+    fn image_file(&self, base: &Path) -> Option<PathBuf> {
+        self.image.as_ref()
+            .filter(|image| !is_url(image))
+            .map(|image| base.join(image))
+    }
+}
+
+fn is_url(image: &str) -> bool {
+    image.starts_with("http://") || image.starts_with("https://")
+}
+
+impl Overrides {
+    /// Load a TOML file, or JSON if the file name ends with `.json`
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let result = if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            serde_json::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&text).map_err(|e| e.to_string())
+        };
+        result.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+struct LoadedOverrides {
+    modified: Option<SystemTime>,
+    overrides: Overrides,
+    last_error: Option<String>,
+}
+
+/// Apply local metadata overrides to the plugins of an inner store.
+/// The overrides file is re-read when it changes, keeping the previous overrides if it becomes invalid.
+pub struct OverrideStorage<S: AsRef<dyn IStorage> + Send + Sync> {
+    inner: S,
+    path: PathBuf,
+    /// Where to serve image files from, so the listed image URL can point at them
+    domain_root: Option<String>,
+    loaded: RwLock<LoadedOverrides>,
+}
+
+impl<S: AsRef<dyn IStorage> + Send + Sync> OverrideStorage<S> {
+    pub fn new(inner: S, path: PathBuf, domain_root: Option<String>) -> Self {
+        let storage = Self {
+            inner,
+            path,
+            domain_root,
+            loaded: RwLock::new(LoadedOverrides {
+                modified: None,
+                overrides: Overrides::default(),
+                last_error: None,
+            }),
+        };
+        storage.loaded(modified(&storage.path), Overrides::load(&storage.path));
+        storage
+    }
+
+    async fn reload_if_changed(&self) {
+        let current = self.loaded.read().expect("Failed to acquire overrides read lock").modified;
+        let path = self.path.clone();
+        // checked on every request, so keep the file system off the async workers
+        let reloaded = actix_web::rt::task::spawn_blocking(move || {
+            let modified = modified(&path);
+            (modified != current).then(|| (modified, Overrides::load(&path)))
+        }).await;
+        match reloaded {
+            Ok(Some((modified, result))) => self.loaded(modified, result),
+            Ok(None) => {},
+            Err(e) => log::error!("Failed to check overrides {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn loaded(&self, modified: Option<SystemTime>, result: std::io::Result<Overrides>) {
+        let mut lock = self.loaded.write().expect("Failed to acquire overrides write lock");
+        lock.modified = modified;
+        match result {
+            Ok(overrides) => {
+                log::info!("Loaded {} plugin overrides from {}", overrides.plugins.len(), self.path.display());
+                lock.overrides = overrides;
+                lock.last_error = None;
+            },
+            Err(e) => {
+                log::error!("Failed to load overrides {}, keeping previous overrides: {}", self.path.display(), e);
+                lock.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn base_folder(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("."))
+    }
+
+    fn apply(&self, plugin: &mut StorePlugin, over: &PluginOverride) {
+        if let Some(id) = over.id {
+            plugin.id = id;
+        }
+        if let Some(author) = &over.author {
+            plugin.author = author.clone();
+        }
+        if let Some(description) = &over.description {
+            plugin.description = description.clone();
+        }
+        plugin.tags.retain(|tag| !over.remove_tags.contains(tag));
+        for tag in &over.add_tags {
+            if !plugin.tags.contains(tag) {
+                plugin.tags.push(tag.clone());
+            }
+        }
+        match &over.image {
+            Some(url) if is_url(url) => plugin.image_url = url.clone(),
+            Some(_) => if let Some(domain_root) = &self.domain_root {
+                plugin.image_url = format!("{}/plugins/{}.png", domain_root, plugin.name);
+            },
+            None => {},
+        }
+    }
+}
+
+#[async_trait]
+impl<S: AsRef<dyn IStorage> + Send + Sync> IStorage for OverrideStorage<S> {
+    async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        self.reload_if_changed().await;
+        let mut plugins = self.inner.as_ref().plugins().await?;
+        let lock = self.loaded.read().expect("Failed to acquire overrides read lock");
+        for plugin in &mut plugins {
+            if let Some(over) = lock.overrides.plugins.get(&plugin.name) {
+                self.apply(plugin, over);
+            }
+        }
+        Ok(plugins)
+    }
+
+    async fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, StorageError> {
+        self.inner.as_ref().get_artifact(name, version, hash).await
+    }
+
+    async fn count_download(&self, name: &str, version: &str, hash: &str) {
+        self.inner.as_ref().count_download(name, version, hash).await
+    }
+
+    async fn get_image(&self, name: &str) -> Result<StorageFile, StorageError> {
+        self.reload_if_changed().await;
+        let image_file = self.loaded.read()
+            .expect("Failed to acquire overrides read lock")
+            .overrides.plugins
+            .get(name)
+            .and_then(|over| over.image_file(self.base_folder()));
+        match image_file {
+            Some(path) => Ok(StorageFile::Path(path)),
+            None => self.inner.as_ref().get_image(name).await,
+        }
+    }
+
+    async fn get_statistics(&self) -> Result<HashMap<String, u64>, StorageError> {
+        self.inner.as_ref().get_statistics().await
+    }
+
+    async fn publish(&self, name: &str, version: &str, upload: PluginUpload) -> Result<decky_api::StorePluginVersion, StorageError> {
+        self.inner.as_ref().publish(name, version, upload).await
+    }
+
+    async fn delete_version(&self, name: &str, version: &str) -> Result<(), StorageError> {
+        self.inner.as_ref().delete_version(name, version).await
+    }
+
+    async fn update_version(&self, name: &str, version: &str, update: VersionUpdate) -> Result<(), StorageError> {
+        self.inner.as_ref().update_version(name, version, update).await
+    }
+
+    async fn health(&self) -> StoreHealth {
+        let start = std::time::Instant::now();
+        self.reload_if_changed().await;
+        let inner = self.inner.as_ref().health().await;
+        let last_error = self.loaded.read().expect("Failed to acquire overrides read lock").last_error.clone();
+        // stale overrides still leave the store usable
+        let status = if last_error.is_some() {
+            inner.status.max(HealthStatus::Degraded)
+        } else {
+            inner.status
+        };
+        StoreHealth::new(format!("overrides {}", self.path.display()), status, last_error, start.elapsed())
+            .with_children(vec![inner])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::EmptyStorage;
+    use crate::test_util::{self, StaticStorage, TempDir};
+
+    #[test]
+    fn apply_overrides() {
+        let overrides: Overrides = toml::from_str(r#"
+            [plugins.PowerTools]
+            id = 42
+            author = "NGnius"
+            add_tags = ["root", "performance"]
+            remove_tags = ["beta"]
+            image = "images/powertools.png"
+        "#).expect("Overrides parse error");
+        let storage = OverrideStorage::new(
+            Box::new(EmptyStorage) as Box<dyn IStorage>,
+            PathBuf::from("/nonexistent/overrides.toml"),
+            Some("http://localhost:22252".to_owned()),
+        );
+        let mut plugin = StorePlugin {
+            id: 7,
+            name: "PowerTools".to_owned(),
+            author: "ngnius".to_owned(),
+            description: "Unchanged".to_owned(),
+            tags: vec!["root".to_owned(), "beta".to_owned()],
+            ..Default::default()
+        };
+        let over = &overrides.plugins["PowerTools"];
+        storage.apply(&mut plugin, over);
+        assert_eq!(plugin.id, 42);
+        assert_eq!(plugin.author, "NGnius");
+        assert_eq!(plugin.description, "Unchanged");
+        assert_eq!(plugin.tags, vec!["root".to_owned(), "performance".to_owned()]);
+        assert_eq!(plugin.image_url, "http://localhost:22252/plugins/PowerTools.png");
+        assert_eq!(over.image_file(storage.base_folder()), Some(PathBuf::from("/nonexistent/images/powertools.png")));
+    }
+
+    #[actix_web::test]
+    async fn reload_changed_file() {
+        let dir = TempDir::new("overrides-reload");
+        let path = dir.join("overrides.toml");
+        let write = |text: &str, age: u64| {
+            std::fs::write(&path, text).unwrap();
+            // the modification time may not change within a test otherwise
+            std::fs::File::options().write(true).open(&path).unwrap()
+                .set_modified(SystemTime::now() - std::time::Duration::from_secs(age)).unwrap();
+        };
+        write("[plugins.PowerTools]\nauthor = \"Jane\"\n", 60);
+        let inner = StaticStorage::new(vec![test_util::plugin("PowerTools", &[("1.0.0", "a")])]);
+        let storage = OverrideStorage::new(inner, path.clone(), None);
+        assert_eq!(storage.plugins().await.unwrap()[0].author, "Jane");
+
+        write("[plugins.PowerTools]\nauthor = \"John\"\nimage = \"powertools.png\"\n", 30);
+        assert_eq!(storage.plugins().await.unwrap()[0].author, "John");
+        write("[plugins.PowerTools]\nauthor = \"Joe\"\nimage = \"other.png\"\n", 20);
+        assert!(matches!(storage.get_image("PowerTools").await.unwrap(), StorageFile::Path(image) if image == dir.join("other.png")));
+
+        // an invalid file keeps the previous overrides
+        write("[plugins.PowerTools", 10);
+        assert_eq!(storage.plugins().await.unwrap()[0].author, "Joe");
+        assert_eq!(storage.health().await.status, HealthStatus::Degraded);
+    }
+}
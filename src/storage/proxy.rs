@@ -1,22 +1,149 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
 use decky_api::{StorePluginList, StorePluginVersion};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::metrics::METRICS;
+
+use super::{Artifact, FileStream, HealthStatus, IStorage, StorageError, StorageFile, StoreHealth};
+use super::fs_util::{safe_file_stem, write_file_atomic, PartialFile};
+use super::version_order::sort_newest_first;
+
+/// How long a pulled image is served before it is downloaded again, since upstream images can change
+const IMAGE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
-use super::IStorage;
+/// How old the last plugin list fetch may be before a health check fetches it again
+const HEALTH_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// How old the last plugin list fetch may be before a request for an unknown artifact fetches it again
+const RELIST_MIN_AGE: Duration = Duration::from_secs(10);
 
 pub struct ProxiedStorage {
     store_url: String,
-    agent: ureq::Agent,
+    client: reqwest::Client,
+    pull_through: Option<PullThrough>,
+    /// Shared with artifact downloads, which finish after `get_artifact` returns
+    upstream: Arc<Mutex<UpstreamHealth>>,
+}
+
+/// Outcome of recent requests to the upstream store, since health checks don't fetch the plugin list themselves
+struct UpstreamHealth {
+    /// `None` until the plugin list has been fetched once
+    status: Option<HealthStatus>,
+    last_error: Option<String>,
+    latency: Duration,
+    /// When the plugin list was last fetched
+    observed: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    fn record(&mut self, status: HealthStatus, error: Option<String>, latency: Option<Duration>) {
+        if let Some(latency) = latency {
+            // the plugin list decides the status, other requests can only make it worse
+            self.status = Some(status);
+            self.latency = latency;
+            self.observed = Some(Instant::now());
+        } else {
+            self.status = self.status.max(Some(status));
+        }
+        if error.is_some() {
+            self.last_error = error;
+        }
+    }
+}
+
+/// Local mirror of upstream artifacts and images, filled in on first request
+struct PullThrough {
+    domain_root: String,
+    cache_root: PathBuf,
+    /// Upstream URLs by `(name, version, hash)` from the last plugin list, since only listed artifacts are pulled
+    artifact_urls: RwLock<HashMap<(String, String, String), String>>,
+    image_urls: RwLock<HashMap<String, String>>,
+    artifact_downloads: InFlight,
+    image_downloads: InFlight,
+}
+
+/// Downloads in progress by key, so concurrent requests for the same file wait for one download
+#[derive(Default)]
+struct InFlight(Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl InFlight {
+    /// Wait until no other download of `key` is running
+    async fn acquire(&self, key: &str) -> FlightGuard {
+        let lock = self.0.lock()
+            .expect("Failed to acquire in-flight downloads lock")
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+        FlightGuard {
+            key: key.to_owned(),
+            guard: Some(lock.lock_owned().await),
+            downloads: self.0.clone(),
+        }
+    }
+}
+
+/// A running download, which lets the next waiting request continue when dropped
+struct FlightGuard {
+    key: String,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+    downloads: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        let mut lock = self.downloads.lock().expect("Failed to acquire in-flight downloads lock");
+        self.guard = None;
+        // nobody else is waiting, since they clone the entry while holding the map lock
+        if lock.get(&self.key).map(|entry| Arc::strong_count(entry) == 1).unwrap_or(false) {
+            lock.remove(&self.key);
+        }
+    }
 }
 
 impl ProxiedStorage {
     pub fn new(target_store: String) -> Self {
         Self {
             store_url: target_store,
-            agent: ureq::AgentBuilder::new()
-                .tls_connector(std::sync::Arc::new(native_tls::TlsConnector::new().expect("Native TLS init failed")))
-                .build(),
+            client: reqwest::Client::builder()
+                .use_native_tls()
+                .build()
+                .expect("Native TLS init failed"),
+            pull_through: None,
+            upstream: Arc::new(Mutex::new(UpstreamHealth {
+                status: None,
+                last_error: None,
+                latency: Duration::ZERO,
+                observed: None,
+            })),
         }
     }
 
+    fn record_upstream(&self, status: HealthStatus, error: Option<String>, latency: Option<Duration>) {
+        self.upstream.lock()
+            .expect("Failed to acquire upstream health lock")
+            .record(status, error, latency);
+    }
+
+    /// Rewrite artifact and image URLs to `domain_root` and serve them from `cache_root`,
+    /// downloading from upstream the first time they are requested.
+    pub fn with_pull_through(mut self, domain_root: String, cache_root: PathBuf) -> Self {
+        self.pull_through = Some(PullThrough {
+            domain_root,
+            cache_root,
+            artifact_urls: RwLock::new(HashMap::new()),
+            image_urls: RwLock::new(HashMap::new()),
+            artifact_downloads: InFlight::default(),
+            image_downloads: InFlight::default(),
+        });
+        self
+    }
+
     fn plugins_url(&self) -> String {
         format!("{}/plugins", self.store_url)
     }
@@ -25,48 +152,475 @@ impl ProxiedStorage {
         format!("https://cdn.tzatzikiweeb.moe/file/steam-deck-homebrew/versions/{}.zip", ver.hash)
     }
 
-    fn proxy_plugins(&self) -> StorePluginList {
+    /// Upstream URL of a listed artifact, fetching the plugin list again if it may have been added since
+    async fn artifact_url(&self, pull_through: &PullThrough, name: &str, version: &str, hash: &str) -> Result<String, StorageError> {
+        if let Some(url) = pull_through.artifact_url(name, version, hash) {
+            return Ok(url);
+        }
+        let observed = self.upstream.lock().expect("Failed to acquire upstream health lock").observed;
+        if observed.map(|at| at.elapsed() > RELIST_MIN_AGE).unwrap_or(true) {
+            self.plugins().await?;
+        }
+        pull_through.artifact_url(name, version, hash)
+            .ok_or_else(|| StorageError::NotFound(format!("Artifact {} of {} v{} is not listed by the upstream store", hash, name, version)))
+    }
+
+    async fn proxy_plugins(&self) -> Result<StorePluginList, StorageError> {
         let url = self.plugins_url();
-        match self.agent.get(&url).call() {
+        let start = Instant::now();
+        match self.client.get(&url).send().await.and_then(|resp| resp.error_for_status()) {
             Err(e) => {
                 log::error!("Plugins proxy error for {}: {}", url, e);
-                vec![]
+                METRICS.upstream_errors.with_label_values(&["plugins"]).inc();
+                self.record_upstream(HealthStatus::Failing, Some(e.to_string()), Some(start.elapsed()));
+                Err(upstream_error(&url, e))
             },
             Ok(resp) => {
-                match resp.into_json::<StorePluginList>() {
+                match resp.json::<Vec<serde_json::Value>>().await {
                     Err(e) => {
                         log::error!("Plugins json error for {}: {}", url, e);
-                        vec![]
+                        METRICS.upstream_errors.with_label_values(&["plugins"]).inc();
+                        self.record_upstream(HealthStatus::Failing, Some(e.to_string()), Some(start.elapsed()));
+                        Err(upstream_error(&url, e))
                     }
-                    Ok(x) => x,
+                    Ok(x) => {
+                        let total = x.len();
+                        // skip malformed entries instead of discarding the whole list
+                        let plugins: StorePluginList = x.into_iter()
+                            .filter_map(|value| match serde_json::from_value(value) {
+                                Ok(plugin) => Some(plugin),
+                                Err(e) => {
+                                    log::warn!("Skipping malformed plugin from {}: {}", url, e);
+                                    None
+                                }
+                            })
+                            .collect();
+                        if plugins.len() == total {
+                            self.record_upstream(HealthStatus::Healthy, None, Some(start.elapsed()));
+                        } else {
+                            let error = format!("Skipped {} malformed plugins", total - plugins.len());
+                            self.record_upstream(HealthStatus::Degraded, Some(error), Some(start.elapsed()));
+                        }
+                        Ok(plugins)
+                    },
+                }
+            }
+        }
+    }
+
+    /// Save an image to the pull-through cache, falling back to serving it from memory
+    async fn store_download(path: PathBuf, data: bytes::Bytes) -> StorageFile {
+        let write_path = path.clone();
+        let write_data = data.clone();
+        let result = actix_web::rt::task::spawn_blocking(move || write_file_atomic(&write_path, &write_data)).await
+            .map_err(std::io::Error::other)
+            .and_then(|result| result);
+        match result {
+            Ok(()) => StorageFile::Path(path),
+            Err(e) => {
+                log::error!("Failed to cache {}, serving it from memory: {}", path.display(), e);
+                StorageFile::Bytes(data)
+            }
+        }
+    }
+
+    /// Start a download from upstream, counting failures as upstream errors for `operation`
+    async fn request(&self, url: &str, operation: &str) -> Result<reqwest::Response, StorageError> {
+        log::debug!("Downloading {} from upstream", url);
+        self.client.get(url).send().await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| self.download_failed(url, operation, e))
+    }
+
+    /// Download a whole file from upstream into memory
+    async fn download(&self, url: &str, operation: &str) -> Result<bytes::Bytes, StorageError> {
+        self.request(url, operation).await?
+            .bytes().await
+            .map_err(|e| self.download_failed(url, operation, e))
+    }
+
+    fn download_failed(&self, url: &str, operation: &str, e: reqwest::Error) -> StorageError {
+        let error = upstream_error(url, e);
+        METRICS.upstream_errors.with_label_values(&[operation]).inc();
+        self.record_upstream(HealthStatus::Degraded, Some(error.to_string()), None);
+        error
+    }
+}
+
+/// An upstream artifact which is checked and written to the pull-through cache while it is sent to the client
+struct ArtifactDownload {
+    response: reqwest::Response,
+    length: Option<u64>,
+    url: String,
+    hash: String,
+    hasher: Sha256,
+    /// `None` once writing to the cache failed
+    cache_file: Option<PartialFile>,
+    upstream: Arc<Mutex<UpstreamHealth>>,
+    /// Held until the download ends, so other requests for it wait and then use the cached file
+    _flight: FlightGuard,
+}
+
+impl ArtifactDownload {
+    fn into_stream(self) -> FileStream {
+        FileStream {
+            length: self.length,
+            chunks: futures_util::stream::unfold(Some(self), |state| async move {
+                let mut download = state?;
+                match download.next_chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk), Some(download))),
+                    Ok(None) => None,
+                    // the file is incomplete or wrong, so end the response early
+                    Err(e) => Some((Err(e), None)),
+                }
+            }).boxed(),
+        }
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<bytes::Bytes>, StorageError> {
+        let chunk = match self.response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return self.finish().await.map(|_| None),
+            Err(e) => return Err(self.failed(upstream_error(&self.url, e))),
+        };
+        self.hasher.update(&chunk);
+        if let Some(mut file) = self.cache_file.take() {
+            let data = chunk.clone();
+            let result = actix_web::rt::task::spawn_blocking(move || file.write(&data).map(|_| file)).await
+                .map_err(std::io::Error::other)
+                .and_then(|result| result);
+            match result {
+                Ok(file) => self.cache_file = Some(file),
+                Err(e) => log::error!("Failed to cache artifact {}, only passing it through: {}", self.hash, e),
+            }
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Check the hash of the complete download and move it into the cache
+    async fn finish(&mut self) -> Result<(), StorageError> {
+        let actual_hash = format!("{:x}", std::mem::take(&mut self.hasher).finalize());
+        if actual_hash != self.hash {
+            log::error!("Artifact from {} has hash {}, expected {}", self.url, actual_hash, self.hash);
+            // dropping the partial file removes it
+            self.cache_file = None;
+            return Err(self.failed(StorageError::Upstream(format!("Upstream artifact {} has hash {}", self.hash, actual_hash))));
+        }
+        if let Some(file) = self.cache_file.take() {
+            let result = actix_web::rt::task::spawn_blocking(move || file.finish()).await
+                .map_err(std::io::Error::other)
+                .and_then(|result| result);
+            if let Err(e) = result {
+                log::error!("Failed to cache artifact {}: {}", self.hash, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn failed(&self, error: StorageError) -> StorageError {
+        METRICS.upstream_errors.with_label_values(&["artifact"]).inc();
+        self.upstream.lock()
+            .expect("Failed to acquire upstream health lock")
+            .record(HealthStatus::Degraded, Some(error.to_string()), None);
+        error
+    }
+}
+
+/// Whether a pulled image exists and is recent enough to serve without asking upstream
+fn is_fresh_image(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        // a modification time in the future counts as fresh
+        .map(|modified| modified.elapsed().map(|age| age < IMAGE_TTL).unwrap_or(true))
+        .unwrap_or(false)
+}
+
+/// Tell an unreachable upstream apart from one which answered with an error
+fn upstream_error(url: &str, e: reqwest::Error) -> StorageError {
+    let message = format!("Upstream error for {}: {}", url, e);
+    if e.is_connect() || e.is_timeout() {
+        StorageError::Unavailable(message)
+    } else if e.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+        StorageError::NotFound(message)
+    } else {
+        StorageError::Upstream(message)
+    }
+}
+
+impl PullThrough {
+    fn artifact_path(&self, hash: &str) -> std::io::Result<PathBuf> {
+        Ok(self.cache_root.join("artifacts").join(format!("{}.zip", safe_file_stem(hash)?)))
+    }
+
+    fn image_path(&self, name: &str) -> std::io::Result<PathBuf> {
+        Ok(self.cache_root.join("images").join(format!("{}.png", safe_file_stem(name)?)))
+    }
+
+    fn artifact_url(&self, name: &str, version: &str, hash: &str) -> Option<String> {
+        self.artifact_urls.read()
+            .expect("Failed to acquire artifact_urls read lock")
+            .get(&(name.to_owned(), version.to_owned(), hash.to_owned()))
+            .cloned()
+    }
+
+    fn rewrite(&self, plugins: &mut StorePluginList) {
+        let mut artifact_urls = HashMap::new();
+        let mut img_lock = self.image_urls.write().expect("Failed to acquire image_urls write lock");
+        for plugin in plugins {
+            for version in &mut plugin.versions {
+                if let Some(upstream) = version.artifact.take() {
+                    artifact_urls.insert((plugin.name.clone(), version.name.clone(), version.hash.clone()), upstream);
                 }
+                version.artifact = Some(format!("{}/plugins/{}/{}/{}.zip", self.domain_root, plugin.name, version.name, version.hash));
             }
+            let upstream_image = std::mem::replace(&mut plugin.image_url, format!("{}/plugins/{}.png", self.domain_root, plugin.name));
+            img_lock.insert(plugin.name.clone(), upstream_image);
         }
+        // versions removed upstream are no longer pulled
+        *self.artifact_urls.write().expect("Failed to acquire artifact_urls write lock") = artifact_urls;
     }
 }
 
+#[async_trait]
 impl IStorage for ProxiedStorage {
-    fn plugins(&self) -> StorePluginList {
-        let mut proxy = self.proxy_plugins();
+    async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        let mut proxy = self.proxy_plugins().await?;
         for plugin in &mut proxy {
+            sort_newest_first(&mut plugin.versions);
             for version in &mut plugin.versions {
                 if version.artifact.is_none() {
                     version.artifact = Some(Self::default_artifact_url(version));
                 }
             }
         }
-        proxy
+        if let Some(pull_through) = &self.pull_through {
+            pull_through.rewrite(&mut proxy);
+        }
+        Ok(proxy)
     }
 
-    /*fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<bytes::Bytes, std::io::Error> {
-        self.fallback.get_artifact(name, version, hash)
+    async fn get_artifact(&self, name: &str, version: &str, hash: &str) -> Result<Artifact, StorageError> {
+        let pull_through = self.pull_through.as_ref()
+            .ok_or_else(|| StorageError::Unsupported("Artifact downloading not supported".to_owned()))?;
+        let url = self.artifact_url(pull_through, name, version, hash).await?;
+        let path = pull_through.artifact_path(hash)?;
+        // only verified downloads are stored
+        let cached = |path: PathBuf| {
+            log::debug!("Serving cached artifact {}", path.display());
+            Ok(Artifact {
+                file: StorageFile::Path(path),
+                hash: hash.to_owned(),
+            })
+        };
+        if path.is_file() {
+            return cached(path);
+        }
+        // a concurrent download of the same artifact may have cached it in the meantime
+        let flight = pull_through.artifact_downloads.acquire(hash).await;
+        if path.is_file() {
+            return cached(path);
+        }
+        let response = self.request(&url, "artifact").await?;
+        let cache_file = match PartialFile::create(&path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::error!("Failed to cache {}, only passing it through: {}", path.display(), e);
+                None
+            }
+        };
+        let download = ArtifactDownload {
+            length: response.content_length(),
+            response,
+            url,
+            hash: hash.to_owned(),
+            hasher: Sha256::new(),
+            cache_file,
+            upstream: self.upstream.clone(),
+            _flight: flight,
+        };
+        Ok(Artifact {
+            file: StorageFile::Stream(download.into_stream()),
+            hash: hash.to_owned(),
+        })
     }
 
-    fn get_image(&self, name: &str) -> Result<bytes::Bytes, std::io::Error> {
-        self.fallback.get_image(name)
+    async fn get_image(&self, name: &str) -> Result<StorageFile, StorageError> {
+        let pull_through = self.pull_through.as_ref()
+            .ok_or_else(|| StorageError::Unsupported("Image downloading not supported".to_owned()))?;
+        let path = pull_through.image_path(name)?;
+        if is_fresh_image(&path) {
+            log::debug!("Serving cached image {}", path.display());
+            return Ok(StorageFile::Path(path));
+        }
+        let _flight = pull_through.image_downloads.acquire(name).await;
+        if is_fresh_image(&path) {
+            return Ok(StorageFile::Path(path));
+        }
+        let url = pull_through.image_urls.read()
+            .expect("Failed to acquire image_urls read lock")
+            .get(name)
+            .cloned();
+        let result = match url {
+            Some(url) => self.download(&url, "image").await,
+            None => Err(StorageError::NotFound("Plugin does not exist in upstream store".to_owned())),
+        };
+        match result {
+            Ok(data) => Ok(Self::store_download(path, data).await),
+            Err(e) if path.is_file() => {
+                log::warn!("Failed to refresh image for {}, serving the cached one: {}", name, e);
+                Ok(StorageFile::Path(path))
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn health(&self) -> StoreHealth {
+        let observed = self.upstream.lock().expect("Failed to acquire upstream health lock").observed;
+        if observed.map(|at| at.elapsed() > HEALTH_MAX_AGE).unwrap_or(true) {
+            // the outcome is recorded in the upstream health
+            let _ = self.proxy_plugins().await;
+        }
+        let lock = self.upstream.lock().expect("Failed to acquire upstream health lock");
+        StoreHealth::new(
+            format!("proxy {}", self.store_url),
+            lock.status.unwrap_or(HealthStatus::Failing),
+            lock.last_error.clone(),
+            lock.latency,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use crate::test_util::{self, TempDir};
+
+    const DOMAIN: &str = "http://localhost:22252";
+    const BAD_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    /// Upstream store listing one plugin, whose older version doesn't match its hash.
+    /// Returns the store URL and a counter of file downloads.
+    fn mock_upstream(artifact: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let plugins = vec![decky_api::StorePlugin {
+            image_url: format!("{}/files/image.png", url),
+            ..test_util::plugin("Example", &[("1.0.0", &sha256::digest(artifact.as_slice())), ("0.9.0", BAD_HASH)])
+        }];
+        let plugins: StorePluginList = plugins.into_iter()
+            .map(|mut plugin| {
+                for version in &mut plugin.versions {
+                    version.artifact = Some(format!("{}/files/{}.zip", url, version.name));
+                }
+                plugin
+            })
+            .collect();
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counter = downloads.clone();
+        let server = HttpServer::new(move || {
+            let (plugins, artifact, counter) = (plugins.clone(), artifact.clone(), counter.clone());
+            App::new()
+                .route("/plugins", web::get().to(move || {
+                    let plugins = plugins.clone();
+                    async move { HttpResponse::Ok().json(plugins) }
+                }))
+                .route("/files/{file}", web::get().to(move |file: web::Path<String>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let body = match file.as_str() {
+                        "image.png" => b"png".to_vec(),
+                        // both versions point at the same file
+                        _ => artifact.clone(),
+                    };
+                    async move { HttpResponse::Ok().body(body) }
+                }))
+        })
+            .workers(1)
+            .disable_signals()
+            .listen(listener)
+            .unwrap();
+        actix_web::rt::spawn(server.run());
+        (url, downloads)
+    }
+
+    async fn read_file(file: StorageFile) -> Result<Vec<u8>, StorageError> {
+        match file {
+            StorageFile::Path(path) => Ok(std::fs::read(path)?),
+            StorageFile::Bytes(bytes) => Ok(bytes.to_vec()),
+            StorageFile::Stream(mut stream) => {
+                let mut data = Vec::new();
+                while let Some(chunk) = stream.chunks.next().await {
+                    data.extend_from_slice(&chunk?);
+                }
+                Ok(data)
+            },
+        }
+    }
+
+    #[actix_web::test]
+    async fn pull_through() {
+        let dir = TempDir::new("proxy-pull-through");
+        let artifact = test_util::zip(&[("Example/plugin.json", r#"{"name": "Example"}"#)]);
+        let hash = sha256::digest(artifact.as_slice());
+        let (upstream, downloads) = mock_upstream(artifact.clone());
+        let proxy = ProxiedStorage::new(upstream).with_pull_through(DOMAIN.to_owned(), dir.join("cache"));
+
+        let plugins = proxy.plugins().await.unwrap();
+        assert_eq!(plugins[0].image_url, format!("{}/plugins/Example.png", DOMAIN));
+        assert_eq!(plugins[0].versions[0].artifact, Some(format!("{}/plugins/Example/1.0.0/{}.zip", DOMAIN, hash)));
+
+        // concurrent requests share a single download
+        let download = || async { read_file(proxy.get_artifact("Example", "1.0.0", &hash).await?.file).await };
+        let (first, second) = futures_util::future::join(download(), download()).await;
+        assert_eq!(first.unwrap(), artifact);
+        assert_eq!(second.unwrap(), artifact);
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+
+        // and later ones are served locally
+        let cached = proxy.get_artifact("Example", "1.0.0", &hash).await.unwrap();
+        assert!(matches!(cached.file, StorageFile::Path(_)));
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+
+        // a download with the wrong hash fails and isn't cached
+        let mismatch = proxy.get_artifact("Example", "0.9.0", BAD_HASH).await.unwrap();
+        assert!(matches!(read_file(mismatch.file).await, Err(StorageError::Upstream(_))));
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+        let cached_files: Vec<_> = std::fs::read_dir(dir.join("cache").join("artifacts")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(cached_files, vec![format!("{}.zip", hash)]);
+
+        // artifacts are only pulled under the name and version they are listed with
+        assert!(matches!(proxy.get_artifact("Example", "0.9.0", &hash).await, Err(StorageError::NotFound(_))));
+        assert!(matches!(proxy.get_artifact("Other", "1.0.0", BAD_HASH).await, Err(StorageError::NotFound(_))));
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+
+        // a restarted proxy fetches the plugin list before pulling
+        let restarted = ProxiedStorage::new(proxy.store_url.clone()).with_pull_through(DOMAIN.to_owned(), dir.join("fresh"));
+        assert_eq!(read_file(restarted.get_artifact("Example", "1.0.0", &hash).await.unwrap().file).await.unwrap(), artifact);
+        assert_eq!(downloads.load(Ordering::SeqCst), 3);
+
+        // images are downloaded again once they expire
+        assert_eq!(read_file(proxy.get_image("Example").await.unwrap()).await.unwrap(), b"png");
+        assert!(matches!(proxy.get_image("Example").await.unwrap(), StorageFile::Path(_)));
+        assert_eq!(downloads.load(Ordering::SeqCst), 4);
+        std::fs::File::options().write(true).open(dir.join("cache").join("images").join("Example.png")).unwrap()
+            .set_modified(std::time::SystemTime::now() - IMAGE_TTL * 2).unwrap();
+        proxy.get_image("Example").await.unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 5);
     }
 
-    fn get_statistics(&self) -> std::collections::HashMap<String, u64> {
-        self.fallback.get_statistics()
-    }*/
+    #[actix_web::test]
+    async fn stale_health() {
+        let proxy = ProxiedStorage::new("http://127.0.0.1:1".to_owned());
+        proxy.record_upstream(HealthStatus::Healthy, None, Some(Duration::from_millis(5)));
+        assert_eq!(proxy.health().await.status, HealthStatus::Healthy);
+
+        // an old observation is checked again
+        proxy.upstream.lock().unwrap().observed = Instant::now().checked_sub(HEALTH_MAX_AGE * 2);
+        let health = proxy.health().await;
+        assert_eq!(health.status, HealthStatus::Failing);
+        assert!(health.last_error.is_some());
+    }
 }
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+use super::fs_util::write_file_atomic;
+
+/// Download counters keyed by artifact hash, persisted as JSON on disk
+pub struct StatsJournal {
+    path: PathBuf,
+    counters: RwLock<HashMap<String, AtomicU64>>,
+    dirty: AtomicBool,
+}
+
+impl StatsJournal {
+    pub fn load(path: PathBuf) -> Self {
+        let counters: HashMap<String, u64> = match File::open(&path) {
+            Ok(file) => match serde_json::from_reader(file) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::error!("`{}` JSON err, starting with empty statistics: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                log::error!("Failed to open statistics journal `{}`: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+        log::info!("Loaded {} download counters from {}", counters.len(), path.display());
+        Self {
+            path,
+            counters: RwLock::new(counters.into_iter().map(|(k, v)| (k, AtomicU64::new(v))).collect()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Periodically write the counters to disk until the journal is dropped
+    pub fn spawn_flusher(self: &Arc<Self>, interval: Duration) {
+        let weak: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Some(journal) = weak.upgrade() {
+                if let Err(e) = journal.flush() {
+                    log::error!("Failed to flush statistics journal `{}`: {}", journal.path.display(), e);
+                }
+            } else {
+                break;
+            }
+        });
+    }
+
+    /// Start tracking downloads for an artifact, if not already tracked
+    pub fn register(&self, hash: &str) {
+        if self.counters.read().expect("Failed to acquire stats read lock").contains_key(hash) {
+            return;
+        }
+        self.counters.write()
+            .expect("Couldn't acquire stats write lock")
+            .entry(hash.to_owned())
+            .or_insert_with(|| AtomicU64::new(0));
+    }
+
+    /// Count a download of a tracked artifact
+    pub fn increment(&self, hash: &str) {
+        let lock = self.counters.read().expect("Failed to acquire stats read lock");
+        if let Some(counter) = lock.get(hash) {
+            counter.fetch_add(1, Ordering::SeqCst);
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<u64> {
+        self.counters.read()
+            .expect("Failed to acquire stats read lock")
+            .get(hash)
+            .map(|count| count.load(Ordering::SeqCst))
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.read().expect("Failed to acquire stats read lock").len()
+    }
+
+    /// Write counters to disk, if they changed since the last flush
+    pub fn flush(&self) -> std::io::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let snapshot: HashMap<String, u64> = self.counters.read()
+            .expect("Failed to acquire stats read lock")
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.load(Ordering::SeqCst)))
+            .collect();
+        let result = self.write_snapshot(&snapshot);
+        if result.is_err() {
+            // try again next time
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    fn write_snapshot(&self, snapshot: &HashMap<String, u64>) -> std::io::Result<()> {
+        let json = serde_json::to_vec(snapshot)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        write_file_atomic(&self.path, &json)?;
+        log::debug!("Flushed {} download counters to {}", snapshot.len(), self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for StatsJournal {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::error!("Failed to flush statistics journal `{}` on shutdown: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn counters_survive_reload() {
+        let dir = TempDir::new("stats-journal");
+        let path = dir.join("stats.json");
+        {
+            let journal = StatsJournal::load(path.clone());
+            journal.register("abc");
+            journal.increment("abc");
+            journal.increment("abc");
+            journal.increment("untracked");
+        }
+        let journal = StatsJournal::load(path.clone());
+        assert_eq!(journal.get("abc"), Some(2));
+        assert_eq!(journal.get("untracked"), None);
+    }
+}
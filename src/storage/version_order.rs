@@ -0,0 +1,103 @@
+use std::cmp::Ordering;
+
+use decky_api::StorePluginVersion;
+
+/// A single dot-separated part of a version name
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Identifier<'a> {
+    // numeric identifiers have lower precedence than alphanumeric ones, like in semver
+    Numeric(u64),
+    Text(&'a str),
+}
+
+impl<'a> Identifier<'a> {
+    fn parse(part: &'a str) -> Self {
+        match part.parse::<u64>() {
+            Ok(n) => Self::Numeric(n),
+            Err(_) => Self::Text(part),
+        }
+    }
+}
+
+/// A loosely parsed semantic version, e.g. `v1.2.3-beta.1+build5`
+#[derive(Debug)]
+struct ParsedVersion<'a> {
+    release: Vec<Identifier<'a>>,
+    pre_release: Vec<Identifier<'a>>,
+}
+
+impl<'a> ParsedVersion<'a> {
+    fn parse(name: &'a str) -> Self {
+        let name = name.trim();
+        let name = name.strip_prefix(|c| c == 'v' || c == 'V').unwrap_or(name);
+        // build metadata does not affect precedence
+        let name = name.split('+').next().unwrap_or(name);
+        let (release, pre_release) = match name.split_once('-') {
+            Some((release, pre_release)) => (release, Some(pre_release)),
+            None => (name, None),
+        };
+        Self {
+            release: release.split('.').map(Identifier::parse).collect(),
+            pre_release: pre_release
+                .map(|pre| pre.split('.').map(Identifier::parse).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn cmp_release(&self, other: &Self) -> Ordering {
+        // missing parts count as 0, so 1.2 == 1.2.0
+        let len = self.release.len().max(other.release.len());
+        for i in 0..len {
+            let a = self.release.get(i).unwrap_or(&Identifier::Numeric(0));
+            let b = other.release.get(i).unwrap_or(&Identifier::Numeric(0));
+            match a.cmp(b) {
+                Ordering::Equal => {},
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+
+    fn cmp_pre_release(&self, other: &Self) -> Ordering {
+        // a pre-release sorts before the release itself
+        match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => self.pre_release.cmp(&other.pre_release),
+        }
+    }
+}
+
+/// Compare version names by semver precedence, tolerating `v` prefixes and missing or non-numeric parts
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parsed_a = ParsedVersion::parse(a);
+    let parsed_b = ParsedVersion::parse(b);
+    parsed_a.cmp_release(&parsed_b)
+        .then_with(|| parsed_a.cmp_pre_release(&parsed_b))
+        // keep the order deterministic for names which only differ in prefix or build metadata
+        .then_with(|| a.cmp(b))
+}
+
+/// Sort versions newest first, which Decky expects when picking the latest version
+pub fn sort_newest_first(versions: &mut [StorePluginVersion]) {
+    versions.sort_by(|a, b| compare_versions(&b.name, &a.name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn semver_ordering() {
+        let mut names = vec![
+            "1.9.0", "v1.10.0", "1.10.0-beta.2", "1.10.0-beta.10", "1.10.0-alpha", "0.1", "2", "1.10.0-beta", "1.2.3+build7",
+        ];
+        names.sort_by(|a, b| compare_versions(b, a));
+        assert_eq!(names, vec![
+            "2", "v1.10.0", "1.10.0-beta.10", "1.10.0-beta.2", "1.10.0-beta", "1.10.0-alpha", "1.9.0", "1.2.3+build7", "0.1",
+        ]);
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Less);
+        assert_eq!(ParsedVersion::parse("1.2").cmp_release(&ParsedVersion::parse("v1.2.0")), Ordering::Equal);
+    }
+}
@@ -0,0 +1,100 @@
+use std::io::{Read, Seek};
+
+use serde_json::Value;
+
+/// Plugin details found in the `plugin.json` and `package.json` files of a Decky plugin zip
+#[derive(Clone, Default, Debug)]
+pub struct ZipMetadata {
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// `publish.image` from `plugin.json`, usually a URL
+    pub image: Option<String>,
+}
+
+impl ZipMetadata {
+    pub fn read(reader: impl Read + Seek) -> std::io::Result<Self> {
+        let mut archive = zip::ZipArchive::new(reader)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let plugin_json = read_json_entry(&mut archive, "plugin.json")?;
+        let package_json = read_json_entry(&mut archive, "package.json")?;
+        if plugin_json.is_none() && package_json.is_none() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Zip contains neither plugin.json nor package.json"));
+        }
+        let plugin_json = plugin_json.unwrap_or(Value::Null);
+        let package_json = package_json.unwrap_or(Value::Null);
+        let publish = &plugin_json["publish"];
+        Ok(Self {
+            author: string_of(&plugin_json["author"])
+                .or_else(|| string_of(&package_json["author"]))
+                .or_else(|| string_of(&package_json["author"]["name"])),
+            description: string_of(&publish["description"])
+                .or_else(|| string_of(&package_json["description"])),
+            tags: strings_of(&publish["tags"])
+                .or_else(|| strings_of(&package_json["keywords"])),
+            image: string_of(&publish["image"]),
+        })
+    }
+}
+
+/// Parse the least nested file called `file_name`, e.g. `PluginName/plugin.json`
+fn read_json_entry<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, file_name: &str) -> std::io::Result<Option<Value>> {
+    let entry_name = archive.file_names()
+        .filter(|name| name.rsplit('/').next() == Some(file_name))
+        .filter(|name| name.matches('/').count() <= 1)
+        .min_by_key(|name| name.len())
+        .map(|name| name.to_owned());
+    if let Some(entry_name) = entry_name {
+        let entry = archive.by_name(&entry_name)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        match serde_json::from_reader(entry) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                log::warn!("Ignoring invalid {} in zip: {}", entry_name, e);
+                Ok(None)
+            }
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+fn string_of(value: &Value) -> Option<String> {
+    value.as_str()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+}
+
+fn strings_of(value: &Value) -> Option<Vec<String>> {
+    value.as_array()
+        .map(|items| items.iter().filter_map(string_of).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn read_decky_plugin_zip() {
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        {
+            let mut writer = zip::ZipWriter::new(&mut buffer);
+            let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            writer.start_file("Example/plugin.json", options).unwrap();
+            writer.write_all(br#"{"name": "Example", "author": "Jane", "publish": {"tags": ["root"], "description": "An example"}}"#).unwrap();
+            writer.start_file("Example/package.json", options).unwrap();
+            writer.write_all(br#"{"name": "example", "author": {"name": "John"}, "description": "Unused", "keywords": ["decky"]}"#).unwrap();
+            writer.start_file("Example/node_modules/dep/package.json", options).unwrap();
+            writer.write_all(br#"{"author": "Someone else"}"#).unwrap();
+            writer.finish().unwrap();
+        }
+        buffer.set_position(0);
+        let metadata = ZipMetadata::read(buffer).expect("Zip metadata error");
+        assert_eq!(metadata.author.as_deref(), Some("Jane"));
+        assert_eq!(metadata.description.as_deref(), Some("An example"));
+        assert_eq!(metadata.tags, Some(vec!["root".to_owned()]));
+        assert_eq!(metadata.image, None);
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_trait::async_trait;
+use decky_api::{StorePlugin, StorePluginList, StorePluginVersion};
+
+use crate::storage::{Artifact, IStorage, StorageError, StorageFile};
+
+/// Empty folder in the system temp dir, removed again when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` must be unique per test, since tests run in parallel within one process
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("not-decky-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Failed to create test folder");
+        Self(path)
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Store with a fixed plugin list, which serves every artifact it is asked for
+pub struct StaticStorage {
+    plugins: StorePluginList,
+    /// Number of times the plugin list was requested
+    pub listings: AtomicUsize,
+    /// Number of downloads counted
+    pub downloads: AtomicUsize,
+}
+
+impl StaticStorage {
+    pub fn new(plugins: StorePluginList) -> Self {
+        Self {
+            plugins,
+            listings: AtomicUsize::new(0),
+            downloads: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Lets wrappers own the store directly, so tests can still inspect it
+impl AsRef<dyn IStorage> for StaticStorage {
+    fn as_ref(&self) -> &(dyn IStorage + 'static) {
+        self
+    }
+}
+
+#[async_trait]
+impl IStorage for StaticStorage {
+    async fn plugins(&self) -> Result<StorePluginList, StorageError> {
+        self.listings.fetch_add(1, Ordering::SeqCst);
+        Ok(self.plugins.clone())
+    }
+
+    async fn get_artifact(&self, _name: &str, _version: &str, hash: &str) -> Result<Artifact, StorageError> {
+        Ok(Artifact {
+            file: StorageFile::Bytes(bytes::Bytes::from_static(b"zip")),
+            hash: hash.to_owned(),
+        })
+    }
+
+    async fn count_download(&self, _name: &str, _version: &str, _hash: &str) {
+        self.downloads.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A zip archive holding the given `(path, contents)` files
+pub fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    let mut writer = zip::ZipWriter::new(&mut buffer);
+    for (path, contents) in files {
+        writer.start_file(*path, zip::write::FileOptions::default()).expect("Failed to add zip entry");
+        writer.write_all(contents.as_bytes()).expect("Failed to write zip entry");
+    }
+    writer.finish().expect("Failed to finish zip");
+    drop(writer);
+    buffer.into_inner()
+}
+
+/// A plugin with `(version, hash)` pairs, where other fields can be set with struct update syntax
+pub fn plugin(name: &str, versions: &[(&str, &str)]) -> StorePlugin {
+    StorePlugin {
+        name: name.to_owned(),
+        versions: versions.iter()
+            .map(|(name, hash)| StorePluginVersion {
+                name: name.to_string(),
+                hash: hash.to_string(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
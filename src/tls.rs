@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use openssl::ssl::{SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod};
+
+/// How often the certificate files are checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// HTTPS certificate and key, swapped for new handshakes when the files change on disk
+pub struct Certificates {
+    cert: PathBuf,
+    key: PathBuf,
+    context: RwLock<SslContext>,
+    modified: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl Certificates {
+    pub fn load(cert: PathBuf, key: PathBuf) -> Result<Arc<Self>, String> {
+        let modified = (modified(&cert), modified(&key));
+        let context = build_context(&cert, &key)?;
+        Ok(Arc::new(Self {
+            cert,
+            key,
+            context: RwLock::new(context),
+            modified: RwLock::new(modified),
+        }))
+    }
+
+    /// An acceptor for `bind_openssl` which always uses the latest certificate
+    pub fn acceptor(self: &Arc<Self>) -> Result<SslAcceptorBuilder, String> {
+        let mut builder = acceptor_builder(&self.cert, &self.key)?;
+        let certs = self.clone();
+        // called for every handshake, whether or not the client sent a server name
+        builder.set_servername_callback(move |ssl, _| {
+            let context = certs.context.read().expect("Failed to acquire certificate read lock");
+            ssl.set_ssl_context(&context).map_err(|_| SniError::ALERT_FATAL)
+        });
+        Ok(builder)
+    }
+
+    /// Load the certificate again if either file changed, keeping the current one if that fails
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let modified = (modified(&self.cert), modified(&self.key));
+        if *self.modified.read().expect("Failed to acquire certificate read lock") == modified {
+            return Ok(false);
+        }
+        // don't retry a broken pair until it changes again
+        *self.modified.write().expect("Failed to acquire certificate write lock") = modified;
+        let context = build_context(&self.cert, &self.key)?;
+        *self.context.write().expect("Failed to acquire certificate write lock") = context;
+        Ok(true)
+    }
+
+    /// Periodically check for new certificates until the last acceptor is dropped
+    pub fn spawn_reloader(self: &Arc<Self>, interval: Duration) {
+        let weak: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Some(certs) = weak.upgrade() {
+                match certs.reload_if_changed() {
+                    Ok(true) => log::info!("Reloaded TLS certificate `{}`", certs.cert.display()),
+                    Ok(false) => {},
+                    Err(e) => log::error!("{}, still using the previous certificate", e),
+                }
+            } else {
+                break;
+            }
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn acceptor_builder(cert: &Path, key: &Path) -> Result<SslAcceptorBuilder, String> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+        .map_err(|e| format!("Failed to set up TLS: {}", e))?;
+    builder.set_certificate_chain_file(cert)
+        .map_err(|e| format!("Failed to load TLS certificate `{}`: {}", cert.display(), e))?;
+    builder.set_private_key_file(key, SslFiletype::PEM)
+        .map_err(|e| format!("Failed to load TLS key `{}`: {}", key.display(), e))?;
+    builder.check_private_key()
+        .map_err(|e| format!("TLS key `{}` does not match certificate `{}`: {}", key.display(), cert.display(), e))?;
+    Ok(builder)
+}
+
+fn build_context(cert: &Path, key: &Path) -> Result<SslContext, String> {
+    Ok(acceptor_builder(cert, key)?.build().into_context())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::{X509, X509NameBuilder}};
+
+    fn write_self_signed(cert: &Path, key: &Path, name: &str) {
+        let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&pkey).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+        std::fs::write(cert, builder.build().to_pem().unwrap()).unwrap();
+        std::fs::write(key, pkey.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    fn common_name(certs: &Certificates) -> String {
+        let context = certs.context.read().unwrap();
+        let cert = context.certificate().unwrap();
+        let entry = cert.subject_name().entries().next().unwrap();
+        entry.data().as_utf8().unwrap().to_string()
+    }
+
+    #[test]
+    fn reload_certificates() {
+        let dir = TempDir::new("tls");
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let err = Certificates::load(cert.clone(), key.clone()).err().unwrap();
+        assert!(err.contains("cert.pem"), "{}", err);
+
+        write_self_signed(&cert, &key, "first");
+        let certs = Certificates::load(cert.clone(), key.clone()).unwrap();
+        assert!(certs.acceptor().is_ok());
+        assert_eq!(certs.reload_if_changed(), Ok(false));
+
+        // a half-written renewal keeps the old certificate
+        std::fs::write(&key, "not a key").unwrap();
+        certs.modified.write().unwrap().1 = None;
+        assert!(certs.reload_if_changed().is_err());
+        assert_eq!(common_name(&certs), "first");
+
+        write_self_signed(&cert, &key, "second");
+        *certs.modified.write().unwrap() = (None, None);
+        assert_eq!(certs.reload_if_changed(), Ok(true));
+        assert_eq!(common_name(&certs), "second");
+    }
+}